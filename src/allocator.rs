@@ -1,22 +1,134 @@
-use std::ptr::NonNull;
+use std::{collections::HashSet, ptr::NonNull};
 
-pub(crate) static LEAKING_ALLOCATOR: LeakingAllocator = LeakingAllocator;
+/// The number of objects allocated before the first garbage collection.
+const INITIAL_THRESHOLD: usize = 1024;
 
-pub(crate) struct LeakingAllocator;
+/// The objects managed by the garbage collector.
+pub(crate) trait Trace {
+    /// Mark the objects referred to by this object.
+    fn trace(&self, tracer: &mut Tracer);
+}
 
-impl LeakingAllocator {
-    pub(crate) fn alloc<T>(&self, value: T) -> NonNull<T> {
-        // SAFETY: Box::into_raw returns non-null pointer
-        unsafe { NonNull::new_unchecked(Box::into_raw(Box::new(value))) }
+/// The state of the mark phase.
+#[derive(Default)]
+pub(crate) struct Tracer {
+    /// The addresses of the objects marked so far.
+    marked: HashSet<*const ()>,
+    /// The objects marked but not traced yet.
+    gray: Vec<NonNull<dyn Trace>>,
+}
+
+impl Tracer {
+    pub(crate) fn mark<T: Trace + 'static>(&mut self, object: NonNull<T>) {
+        if self.marked.insert(object.as_ptr() as *const ()) {
+            self.gray.push(object);
+        }
     }
+}
 
-    pub(crate) fn alloc_array<T: Clone>(&self, init: T, num: usize) -> NonNull<[T]> {
-        // SAFETY: Box::into_raw returns non-null pointer
-        unsafe { NonNull::new_unchecked(Box::into_raw(vec![init; num].into_boxed_slice())) }
+/// The mark-and-sweep garbage collected heap.
+pub(crate) struct Heap {
+    objects: Vec<NonNull<dyn Trace>>,
+    /// The number of objects that triggers the next collection.
+    threshold: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: vec![],
+            threshold: INITIAL_THRESHOLD,
+        }
     }
+}
 
-    pub(crate) fn alloc_empty_array<T>(&self) -> NonNull<[T]> {
+impl Heap {
+    pub(crate) fn alloc<T: Trace + 'static>(&mut self, value: T) -> NonNull<T> {
         // SAFETY: Box::into_raw returns non-null pointer
-        unsafe { NonNull::new_unchecked(Box::into_raw(vec![].into_boxed_slice())) }
+        let object = unsafe { NonNull::new_unchecked(Box::into_raw(Box::new(value))) };
+        self.objects.push(object);
+        object
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub(crate) fn should_collect(&self) -> bool {
+        // Collect on every check in tests so that missing roots are caught early.
+        cfg!(test) || self.objects.len() >= self.threshold
+    }
+
+    /// Reclaim the objects not reachable from the roots marked by `mark_roots`.
+    ///
+    /// # Safety
+    /// All objects reachable from the live values must be reachable from the roots.
+    pub(crate) unsafe fn collect(&mut self, mark_roots: impl FnOnce(&mut Tracer)) {
+        let mut tracer = Tracer::default();
+        mark_roots(&mut tracer);
+        while let Some(object) = tracer.gray.pop() {
+            // SAFETY: the marked objects are reachable, so they are not reclaimed yet.
+            unsafe { object.as_ref().trace(&mut tracer) };
+        }
+
+        self.objects.retain(|object| {
+            if tracer.marked.contains(&(object.as_ptr() as *const ())) {
+                true
+            } else {
+                // SAFETY: the object is allocated by Box in alloc(), and no one refers to it.
+                drop(unsafe { Box::from_raw(object.as_ptr()) });
+                false
+            }
+        });
+        self.threshold = INITIAL_THRESHOLD.max(2 * self.objects.len());
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for object in self.objects.drain(..) {
+            // SAFETY: the object is allocated by Box in alloc(), and the heap outlives the references.
+            drop(unsafe { Box::from_raw(object.as_ptr()) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_heap {
+    use std::ptr::NonNull;
+
+    use super::{Heap, Trace, Tracer};
+
+    struct Node {
+        next: Option<NonNull<Node>>,
+    }
+
+    impl Trace for Node {
+        fn trace(&self, tracer: &mut Tracer) {
+            if let Some(next) = self.next {
+                tracer.mark(next);
+            }
+        }
+    }
+
+    #[test]
+    fn test_collect() {
+        let mut heap = Heap::default();
+        let tail = heap.alloc(Node { next: None });
+        let head = heap.alloc(Node { next: Some(tail) });
+        let garbage = heap.alloc(Node { next: Some(head) });
+        let _unreachable = heap.alloc(Node {
+            next: Some(garbage),
+        });
+        assert_eq!(heap.len(), 4);
+
+        // SAFETY: head is the only live object.
+        unsafe { heap.collect(|tracer| tracer.mark(head)) };
+        assert_eq!(heap.len(), 2);
+
+        // SAFETY: nothing is live.
+        unsafe { heap.collect(|_| {}) };
+        assert_eq!(heap.len(), 0);
     }
 }
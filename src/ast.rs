@@ -39,29 +39,105 @@ impl<'arena> Ast<'arena> {
 pub(crate) enum AstBody<'arena> {
     Root(Vec<Ast<'arena>>),
     // Expressions
+    Nil,
+    Boolean(bool),
     Number(f64),
     String(String),
+    /// A string literal with interpolations, whose parts are concatenated.
+    Interpolation(Vec<Ast<'arena>>),
+    List(Vec<Ast<'arena>>),
+    /// A map literal with the pairs of the keys and the values.
+    Map(Vec<(Ast<'arena>, Ast<'arena>)>),
+    Not(Ast<'arena>),
+    Negate(Ast<'arena>),
     Add(Ast<'arena>, Ast<'arena>),
     Sub(Ast<'arena>, Ast<'arena>),
     Mul(Ast<'arena>, Ast<'arena>),
     Div(Ast<'arena>, Ast<'arena>),
+    Equal(Ast<'arena>, Ast<'arena>),
+    NotEqual(Ast<'arena>, Ast<'arena>),
+    Less(Ast<'arena>, Ast<'arena>),
+    LessEqual(Ast<'arena>, Ast<'arena>),
+    Greater(Ast<'arena>, Ast<'arena>),
+    GreaterEqual(Ast<'arena>, Ast<'arena>),
+    And(Ast<'arena>, Ast<'arena>),
+    Or(Ast<'arena>, Ast<'arena>),
     Assign(String, Ast<'arena>),
     Var(String),
+    This,
+    /// The method of the superclass bound to `this`.
+    Super(String),
     Call {
         callee: Ast<'arena>,
         arguments: Vec<Ast<'arena>>,
     },
+    /// An anonymous function.
+    Lambda {
+        parameters: Vec<String>,
+        body: Vec<Ast<'arena>>,
+    },
+    GetProperty {
+        object: Ast<'arena>,
+        name: String,
+    },
+    SetProperty {
+        object: Ast<'arena>,
+        name: String,
+        value: Ast<'arena>,
+    },
+    GetIndex {
+        object: Ast<'arena>,
+        index: Ast<'arena>,
+    },
+    SetIndex {
+        object: Ast<'arena>,
+        index: Ast<'arena>,
+        value: Ast<'arena>,
+    },
+    Reset(Ast<'arena>),
+    Shift(Ast<'arena>),
     // Declarations
     VarDecl {
+        /// Whether the variable is declared with `export`.
+        exported: bool,
         ident: String,
         initializer: Option<Ast<'arena>>,
     },
     FunDecl {
+        /// Whether the function is declared with `export`.
+        exported: bool,
         ident: String,
         parameters: Vec<String>,
         body: Vec<Ast<'arena>>,
     },
+    ClassDecl {
+        /// Whether the class is declared with `export`.
+        exported: bool,
+        ident: String,
+        superclass: Option<Ast<'arena>>,
+        /// The methods, represented as function declarations.
+        methods: Vec<Ast<'arena>>,
+    },
     // Statements
+    Block(Vec<Ast<'arena>>),
+    If {
+        condition: Ast<'arena>,
+        then_branch: Vec<Ast<'arena>>,
+        else_branch: Option<Vec<Ast<'arena>>>,
+    },
+    While {
+        condition: Ast<'arena>,
+        body: Vec<Ast<'arena>>,
+    },
+    For {
+        initializer: Option<Ast<'arena>>,
+        condition: Option<Ast<'arena>>,
+        /// The assignment or the expression statement run after each iteration.
+        increment: Option<Ast<'arena>>,
+        body: Vec<Ast<'arena>>,
+    },
+    Break,
+    Continue,
     ExprStmt {
         expr: Ast<'arena>,
     },
@@ -3,8 +3,10 @@ use std::{
     rc::Rc,
 };
 
+use chumsky::prelude::Simple;
+
 use crate::{
-    ast::{Ast, AstBody},
+    ast::{Ast, AstBody, Span},
     constant::{Constant, Function},
     opcode::{ChunkBuilder, OpCode},
     parser::LineMapper,
@@ -64,13 +66,53 @@ impl Upvalue {
     }
 }
 
+/// How the declarations at the top level of a script are bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TopLevel {
+    /// Every outermost declaration defines a global.
+    Global,
+    /// Every declaration is a local private to the script unless it is declared with `export`.
+    Private,
+}
+
+/// The kind of the compiling function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    /// A function declaration, a lambda, or the top-level code.
+    Function,
+    /// A method, which receives `this` in the slot following the return continuation.
+    Method,
+    /// The `init` method, which returns `this`.
+    Initializer,
+}
+
+/// The loop enclosing the compiling statement.
+struct Loop {
+    /// The level of the locals outside the loop body.
+    level: usize,
+    /// The positions of the jumps of `break` to patch at the exit of the loop.
+    breaks: Vec<usize>,
+    /// The positions of the jumps of `continue` to patch at the end of the loop body.
+    continues: Vec<usize>,
+}
+
 struct Compiler<'parent> {
     builder: ChunkBuilder,
     /// The list of locals visible by the compiling block, sorted by level.
     locals: Vec<Local>,
     /// The current level of the locals.
     current_level: usize,
+    /// The number of parameters of the compiling function.
+    arity: usize,
+    kind: FunctionKind,
     upvalues: RefCell<Vec<Upvalue>>,
+    /// The stack of the loops enclosing the compiling statement, the innermost one comes last.
+    loops: Vec<Loop>,
+    errors: Vec<Simple<char>>,
+    /// How the outermost declarations are bound. Only used for the top-level code.
+    top_level: TopLevel,
+    /// Whether the constants of the chunk overflowed, which is reported only once.
+    constants_overflowed: bool,
     parent: Option<&'parent Compiler<'parent>>,
 }
 
@@ -86,17 +128,34 @@ impl<'parent> Compiler<'parent> {
             builder: ChunkBuilder::default(),
             locals: vec![Local::cont()],
             current_level: 0,
+            arity: 0,
+            kind: FunctionKind::Function,
             upvalues: RefCell::new(vec![]),
+            loops: vec![],
+            errors: vec![],
+            top_level: TopLevel::Global,
+            constants_overflowed: false,
             parent,
         };
         this.begin_scope();
         this
     }
 
-    fn with_parent(parameters: &[String], parent: &'parent Compiler<'parent>) -> Self {
+    /// Create the compiler of a function, where `span` is the declaration of the function.
+    fn with_parent(
+        kind: FunctionKind,
+        parameters: &[String],
+        span: Span,
+        parent: &'parent Compiler<'parent>,
+    ) -> Self {
         let mut this = Self::new(Some(parent));
+        this.arity = parameters.len();
+        this.kind = kind;
+        if kind != FunctionKind::Function {
+            this.push_local("this", span);
+        }
         for param in parameters.iter() {
-            this.push_local(param);
+            this.push_local(param, span);
         }
         this
     }
@@ -104,13 +163,12 @@ impl<'parent> Compiler<'parent> {
     /// Look up the given identifier from the local variables slots of this function.
     ///
     /// Returns `Some(index)` when a local variable with the same name is found.
-    /// Returns `None` when not found.
+    /// Returns `None` when not found, or when its slot is out of range, which `push_local` reports.
     fn lookup_local(&self, ident: &str) -> Option<u8> {
-        // TODO: handle errors when index overflows
         self.locals
             .iter()
             .rposition(|local| local.matches(ident))
-            .map(|index| u8::try_from(index).unwrap())
+            .and_then(|index| u8::try_from(index).ok())
     }
 
     fn mark_captured(&self, index: u8) {
@@ -172,7 +230,12 @@ impl<'parent> Compiler<'parent> {
 
     fn build(mut self, name: String) -> (Function, Vec<Upvalue>) {
         let upvalues = self.upvalues.into_inner();
-        let function = Function::new(name, Rc::new(self.builder.build()), upvalues.len());
+        let function = Function::new(
+            name,
+            self.arity,
+            Rc::new(self.builder.build()),
+            upvalues.len(),
+        );
         (function, upvalues)
     }
 
@@ -198,31 +261,139 @@ impl<'parent> Compiler<'parent> {
         self.current_level -= 1;
     }
 
-    fn push_local(&mut self, ident: &str) {
+    /// Emit OP_POP or OP_CLOSE_UPVALUE for the locals above the given level without forgetting them,
+    /// so that `break` and `continue` can jump out of the scopes.
+    fn discard_locals(&mut self, level: usize, line: usize) {
+        for local in self.locals.iter().rev() {
+            if local.level <= level {
+                break;
+            }
+
+            if local.captured.get() {
+                self.builder.push_op(OpCode::CloseUpvalue, line);
+            } else {
+                self.builder.push_op(OpCode::Pop, line);
+            }
+        }
+    }
+
+    /// Report the error of emitting a jump at the span, unless it's just reported for the same span.
+    fn check_jump(&mut self, result: Result<(), String>, span: Span) {
+        if let Err(message) = result {
+            let error = Simple::custom(span.start..span.end, message);
+            if self.errors.last() != Some(&error) {
+                self.errors.push(error);
+            }
+        }
+    }
+
+    /// Push the constant to the chunk, where `span` is the code using it.
+    fn push_constant(&mut self, constant: Constant, span: Span) -> u8 {
+        match self.builder.push_constant(constant) {
+            Ok(index) => index,
+            Err(message) => {
+                if !self.constants_overflowed {
+                    self.constants_overflowed = true;
+                    self.errors
+                        .push(Simple::custom(span.start..span.end, message));
+                }
+                u8::MAX
+            }
+        }
+    }
+
+    /// Patch the jump to jump to the next instruction, where `span` is the statement containing the jump.
+    fn patch_jump(&mut self, position: usize, span: Span) {
+        let result = self.builder.patch_jump(position);
+        self.check_jump(result, span);
+    }
+
+    /// Compile the loop body and the increment, then jump back to `start`.
+    ///
+    /// The body may jump out of the loop with `break`, whose jumps are returned to be patched by the caller.
+    fn push_loop_body(
+        &mut self,
+        start: usize,
+        body: &[Ast<'_>],
+        increment: Option<Ast<'_>>,
+        mapper: &LineMapper,
+        span: Span,
+    ) -> Vec<usize> {
+        let line = mapper.find(span.end);
+        self.loops.push(Loop {
+            level: self.current_level,
+            breaks: vec![],
+            continues: vec![],
+        });
+        self.push_block(body, mapper, line);
+        let Loop {
+            breaks, continues, ..
+        } = self.loops.pop().unwrap();
+
+        for continue_jump in continues {
+            self.patch_jump(continue_jump, span);
+        }
+        if let Some(increment) = increment {
+            self.push(increment, mapper);
+        }
+        let result = self.builder.push_loop(start, line);
+        self.check_jump(result, span);
+
+        breaks
+    }
+
+    /// Declare the local in the next slot, where `span` is the declaration of the local.
+    fn push_local(&mut self, ident: &str, span: Span) {
+        // The slot index is an 8-bit operand, so report the first local beyond it.
+        if self.locals.len() == usize::from(u8::MAX) + 1 {
+            self.errors.push(Simple::custom(
+                span.start..span.end,
+                "too many local variables in a function",
+            ));
+        }
         self.locals
             .push(Local::new(ident.into(), self.current_level));
     }
 
+    /// Whether the compiling statement is at the outermost scope of the top-level code.
+    fn at_top_level(&self) -> bool {
+        self.parent.is_none() && self.current_level == 1
+    }
+
+    /// Report `export` used anywhere except at the top level.
+    fn check_export(&mut self, exported: bool, span: Span) {
+        if exported && !self.at_top_level() {
+            self.errors.push(Simple::custom(
+                span.start..span.end,
+                "export is only allowed at the top level",
+            ));
+        }
+    }
+
+    /// Whether the declaration at the compiling statement defines a global.
+    fn declares_global(&self, exported: bool) -> bool {
+        exported || (self.at_top_level() && self.top_level == TopLevel::Global)
+    }
+
     /// The function to handle local/global variable declarations.
     ///
     /// Call this function after emitting the initializer.
-    fn define_variable(&mut self, ident: &str, line: usize) {
-        if self.parent.is_some() {
-            // Treat the var declaration as local only if it's in a function.
+    fn define_variable(&mut self, ident: &str, exported: bool, span: Span, line: usize) {
+        if !self.declares_global(exported) {
             // The slot for the local is already allocated on the stack.
-            self.push_local(ident);
+            self.push_local(ident, span);
         } else {
             // For global variables, we need to emit SET_GLOBAL.
-            let index = self.builder.push_constant(Constant::String(ident.into()));
+            let index = self.push_constant(Constant::String(ident.into()), span);
             self.builder.push_op(OpCode::SetGlobal, line);
             self.builder.push_u8(index, line);
         }
     }
 
-    fn emit_set(&mut self, ident: &str, line: usize) {
+    fn emit_set(&mut self, ident: &str, span: Span, line: usize) {
         match self.lookup(ident) {
             LookupResult::NotFound => {
-                let index = self.builder.push_constant(Constant::String(ident.into()));
+                let index = self.push_constant(Constant::String(ident.into()), span);
                 self.builder.push_op(OpCode::SetGlobal, line);
                 self.builder.push_u8(index, line);
             }
@@ -239,12 +410,204 @@ impl<'parent> Compiler<'parent> {
 
     fn emit_return(&mut self, return_value: Option<Ast<'_>>, mapper: &LineMapper, line: usize) {
         match return_value {
-            Some(return_value) => self.push(return_value, mapper),
+            Some(return_value) => {
+                if self.kind == FunctionKind::Initializer {
+                    self.errors.push(Simple::custom(
+                        return_value.span.start..return_value.span.end,
+                        "cannot return a value from an initializer",
+                    ));
+                }
+                self.push(return_value, mapper)
+            }
+            // Initializers return the instance.
+            None if self.kind == FunctionKind::Initializer => {
+                self.builder.push_op(OpCode::GetLocal, line);
+                self.builder.push_u8(1, line);
+            }
             None => self.builder.push_op(OpCode::Nil, line),
         }
         self.builder.push_op(OpCode::Return, line);
     }
 
+    fn emit_get(&mut self, ident: &str, span: Span, line: usize) {
+        match self.lookup(ident) {
+            LookupResult::NotFound => {
+                let index = self.push_constant(Constant::String(ident.into()), span);
+                self.builder.push_op(OpCode::GetGlobal, line);
+                self.builder.push_u8(index, line);
+            }
+            LookupResult::Local(index) => {
+                self.builder.push_op(OpCode::GetLocal, line);
+                self.builder.push_u8(index, line);
+            }
+            LookupResult::Upvalue(index) => {
+                self.builder.push_op(OpCode::GetUpvalue, line);
+                self.builder.push_u8(index, line);
+            }
+        }
+    }
+
+    /// Check that `this` or `super`, which are declared as locals by the classes, is available here.
+    fn check_implicit(&mut self, ident: &str, message: &str, span: Span) -> bool {
+        if let LookupResult::NotFound = self.lookup(ident) {
+            self.errors
+                .push(Simple::custom(span.start..span.end, message));
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Emit the instruction taking the name of the property as the operand.
+    fn emit_named(&mut self, opcode: OpCode, name: &str, span: Span, line: usize) {
+        let index = self.push_constant(Constant::String(name.into()), span);
+        self.builder.push_op(opcode, line);
+        self.builder.push_u8(index, line);
+    }
+
+    /// Compile the statements in a new scope.
+    fn push_block(&mut self, stmts: &[Ast<'_>], mapper: &LineMapper, line: usize) {
+        self.begin_scope();
+        self.push_stmts(stmts, mapper);
+        self.end_scope(line);
+    }
+
+    /// Compile the statements in order.
+    ///
+    /// Each run of adjacent local function declarations is compiled at once,
+    /// so that the functions can call themselves and each other.
+    /// The private top-level functions are all declared ahead instead,
+    /// so that they can also call the functions declared later in the file.
+    fn push_stmts(&mut self, stmts: &[Ast<'_>], mapper: &LineMapper) {
+        let is_local_function = |compiler: &Self, stmt: &Ast<'_>| match stmt.body {
+            AstBody::FunDecl { exported, .. } => !compiler.declares_global(*exported),
+            _ => false,
+        };
+        let mut hoisted_slot = None;
+        if self.at_top_level() && self.top_level == TopLevel::Private {
+            let decls: Vec<_> = stmts
+                .iter()
+                .filter(|stmt| is_local_function(self, stmt))
+                .copied()
+                .collect();
+            hoisted_slot = Some(self.declare_local_functions(&decls, mapper));
+        }
+
+        let mut rest = stmts;
+        while let Some(stmt) = rest.first() {
+            let run = rest
+                .iter()
+                .take_while(|stmt| is_local_function(self, stmt))
+                .count();
+            if run == 0 {
+                self.push(*stmt, mapper);
+                rest = &rest[1..];
+            } else {
+                let first_slot = match &mut hoisted_slot {
+                    Some(slot) => {
+                        let first_slot = *slot;
+                        *slot += run;
+                        first_slot
+                    }
+                    None => self.declare_local_functions(&rest[..run], mapper),
+                };
+                self.define_local_functions(&rest[..run], first_slot, mapper);
+                rest = &rest[run..];
+            }
+        }
+    }
+
+    /// Declare the locals of the functions with nil, and return the slot of the first one.
+    ///
+    /// The locals are declared before compiling the functions so that their bodies capture them.
+    fn declare_local_functions(&mut self, decls: &[Ast<'_>], mapper: &LineMapper) -> usize {
+        let first_slot = self.locals.len();
+        for decl in decls.iter() {
+            if let AstBody::FunDecl { ident, .. } = decl.body {
+                self.builder
+                    .push_op(OpCode::Nil, mapper.find(decl.span.start));
+                self.push_local(ident, decl.span);
+            }
+        }
+        first_slot
+    }
+
+    /// Compile the declared local functions, and set each local from the first slot to its closure.
+    fn define_local_functions(
+        &mut self,
+        decls: &[Ast<'_>],
+        first_slot: usize,
+        mapper: &LineMapper,
+    ) {
+        for (offset, decl) in decls.iter().enumerate() {
+            if let AstBody::FunDecl {
+                ident,
+                parameters,
+                body,
+                ..
+            } = decl.body
+            {
+                let start_line = mapper.find(decl.span.start);
+                self.push_function(
+                    FunctionKind::Function,
+                    ident.clone(),
+                    parameters,
+                    body,
+                    decl.span,
+                    mapper,
+                );
+                // Too many locals are already reported by `push_local`.
+                let slot = u8::try_from(first_slot + offset).unwrap_or(u8::MAX);
+                self.builder.push_op(OpCode::SetLocal, start_line);
+                self.builder.push_u8(slot, start_line);
+            }
+        }
+    }
+
+    /// Compile the function and emit it, or the closure capturing its upvalues.
+    fn push_function(
+        &mut self,
+        kind: FunctionKind,
+        name: String,
+        parameters: &[String],
+        body: &[Ast<'_>],
+        span: Span,
+        mapper: &LineMapper,
+    ) {
+        let start_line = mapper.find(span.start);
+        let end_line = mapper.find(span.end);
+        let mut fun_compiler = Compiler::with_parent(kind, parameters, span, self);
+        fun_compiler.push_stmts(body, mapper);
+        // TODO: explicit returnがあるときここは無駄
+        // Initializers keep `this` in the slot to return it, and OP_RETURN drops the locals anyway.
+        if kind != FunctionKind::Initializer {
+            fun_compiler.end_scope(end_line);
+        }
+        fun_compiler.emit_return(None, mapper, end_line);
+        let errors = std::mem::take(&mut fun_compiler.errors);
+        let (function, upvalues) = fun_compiler.build(name);
+        self.errors.extend(errors);
+
+        let fun_const_index = self.push_constant(Constant::Function(function), span);
+        self.builder.push_op(OpCode::Constant, start_line);
+        self.builder.push_u8(fun_const_index, start_line);
+
+        let upvalues_len = upvalues.len();
+        if upvalues_len > 0 {
+            self.builder.push_op(OpCode::Closure, start_line);
+            self.builder
+                .push_u8(u8::try_from(upvalues_len).unwrap(), start_line);
+            for upvalue in upvalues.iter() {
+                let (is_local, index) = match *upvalue {
+                    Upvalue::InLocal { index } => (1, index),
+                    Upvalue::InUpvalue { index } => (0, index),
+                };
+                self.builder.push_u8(is_local, start_line);
+                self.builder.push_u8(index, start_line);
+            }
+        }
+    }
+
     fn push_binop(&mut self, opcode: OpCode, lhs: Ast<'_>, rhs: Ast<'_>, mapper: &LineMapper) {
         self.push(lhs, mapper);
         self.push(rhs, mapper);
@@ -255,98 +618,403 @@ impl<'parent> Compiler<'parent> {
         let start_line = mapper.find(ast.span.start);
         let end_line = mapper.find(ast.span.end);
         match ast.body {
+            AstBody::Nil => self.builder.push_op(OpCode::Nil, start_line),
+            AstBody::Boolean(true) => self.builder.push_op(OpCode::True, start_line),
+            AstBody::Boolean(false) => self.builder.push_op(OpCode::False, start_line),
             AstBody::Number(number) => {
-                let index = self.builder.push_constant(Constant::Number(*number));
+                let index = self.push_constant(Constant::Number(*number), ast.span);
                 self.builder.push_op(OpCode::Constant, start_line);
                 self.builder.push_u8(index, start_line);
             }
             AstBody::String(string) => {
-                let index = self.builder.push_constant(Constant::String(string.clone()));
+                let index = self.push_constant(Constant::String(string.clone()), ast.span);
                 self.builder.push_op(OpCode::Constant, start_line);
                 self.builder.push_u8(index, start_line);
             }
+            AstBody::Interpolation(parts) => {
+                let parts_len = match u8::try_from(parts.len()) {
+                    Ok(parts_len) => parts_len,
+                    Err(_) => {
+                        self.errors.push(Simple::custom(
+                            ast.span.start..ast.span.end,
+                            "too many parts in an interpolated string",
+                        ));
+                        return;
+                    }
+                };
+                for part in parts.iter() {
+                    self.push(*part, mapper);
+                }
+                self.builder.push_op(OpCode::Concat, start_line);
+                self.builder.push_u8(parts_len, start_line);
+            }
+            AstBody::List(elements) => {
+                let elements_len = match u8::try_from(elements.len()) {
+                    Ok(elements_len) => elements_len,
+                    Err(_) => {
+                        self.errors.push(Simple::custom(
+                            ast.span.start..ast.span.end,
+                            "too many elements in a list literal",
+                        ));
+                        return;
+                    }
+                };
+                for element in elements.iter() {
+                    self.push(*element, mapper);
+                }
+                self.builder.push_op(OpCode::List, start_line);
+                self.builder.push_u8(elements_len, start_line);
+            }
+            AstBody::Map(entries) => {
+                let entries_len = match u8::try_from(entries.len()) {
+                    Ok(entries_len) => entries_len,
+                    Err(_) => {
+                        self.errors.push(Simple::custom(
+                            ast.span.start..ast.span.end,
+                            "too many entries in a map literal",
+                        ));
+                        return;
+                    }
+                };
+                for (key, value) in entries.iter() {
+                    self.push(*key, mapper);
+                    self.push(*value, mapper);
+                }
+                self.builder.push_op(OpCode::Map, start_line);
+                self.builder.push_u8(entries_len, start_line);
+            }
             AstBody::Print(expr) => {
                 self.push(*expr, mapper);
                 self.builder.push_op(OpCode::Print, start_line);
             }
+            AstBody::Not(expr) => {
+                self.push(*expr, mapper);
+                self.builder.push_op(OpCode::Not, start_line);
+            }
+            AstBody::Negate(expr) => {
+                self.push(*expr, mapper);
+                self.builder.push_op(OpCode::Negate, start_line);
+            }
             AstBody::Add(lhs, rhs) => self.push_binop(OpCode::Add, *lhs, *rhs, mapper),
             AstBody::Sub(lhs, rhs) => self.push_binop(OpCode::Sub, *lhs, *rhs, mapper),
             AstBody::Mul(lhs, rhs) => self.push_binop(OpCode::Mul, *lhs, *rhs, mapper),
             AstBody::Div(lhs, rhs) => self.push_binop(OpCode::Div, *lhs, *rhs, mapper),
-            AstBody::Root(stmts) => {
-                for stmt in stmts.iter() {
-                    self.push(*stmt, mapper);
-                }
+            AstBody::Equal(lhs, rhs) => self.push_binop(OpCode::Equal, *lhs, *rhs, mapper),
+            AstBody::NotEqual(lhs, rhs) => self.push_binop(OpCode::NotEqual, *lhs, *rhs, mapper),
+            AstBody::Less(lhs, rhs) => self.push_binop(OpCode::Less, *lhs, *rhs, mapper),
+            AstBody::LessEqual(lhs, rhs) => self.push_binop(OpCode::LessEqual, *lhs, *rhs, mapper),
+            AstBody::Greater(lhs, rhs) => self.push_binop(OpCode::Greater, *lhs, *rhs, mapper),
+            AstBody::GreaterEqual(lhs, rhs) => {
+                self.push_binop(OpCode::GreaterEqual, *lhs, *rhs, mapper)
             }
+            AstBody::And(lhs, rhs) => {
+                // The right operand is evaluated only if the left one is truthy.
+                self.push(*lhs, mapper);
+                let end_jump = self.builder.push_jump(OpCode::JumpIfFalse, start_line);
+                self.builder.push_op(OpCode::Pop, start_line);
+                self.push(*rhs, mapper);
+                self.patch_jump(end_jump, ast.span);
+            }
+            AstBody::Or(lhs, rhs) => {
+                // The right operand is evaluated only if the left one is falsy.
+                self.push(*lhs, mapper);
+                let else_jump = self.builder.push_jump(OpCode::JumpIfFalse, start_line);
+                let end_jump = self.builder.push_jump(OpCode::Jump, start_line);
+                self.patch_jump(else_jump, ast.span);
+                self.builder.push_op(OpCode::Pop, start_line);
+                self.push(*rhs, mapper);
+                self.patch_jump(end_jump, ast.span);
+            }
+            AstBody::Root(stmts) => self.push_stmts(stmts, mapper),
             AstBody::Assign(ident, expr) => {
                 self.push(*expr, mapper);
-                self.emit_set(ident, start_line);
+                self.emit_set(ident, ast.span, start_line);
             }
-            AstBody::Var(ident) => match self.lookup(ident) {
-                LookupResult::NotFound => {
-                    let index = self.builder.push_constant(Constant::String(ident.clone()));
-                    self.builder.push_op(OpCode::GetGlobal, start_line);
-                    self.builder.push_u8(index, start_line);
+            AstBody::Var(ident) => self.emit_get(ident, ast.span, start_line),
+            AstBody::This => {
+                if self.check_implicit("this", "this outside of a method", ast.span) {
+                    self.emit_get("this", ast.span, start_line);
                 }
-                LookupResult::Local(index) => {
-                    self.builder.push_op(OpCode::GetLocal, start_line);
-                    self.builder.push_u8(index, start_line);
-                }
-                LookupResult::Upvalue(index) => {
-                    self.builder.push_op(OpCode::GetUpvalue, start_line);
-                    self.builder.push_u8(index, start_line);
+            }
+            AstBody::Super(name) => {
+                // `super` is declared only around the methods of subclasses, where `this` is declared as well.
+                if self.check_implicit("super", "super outside of a subclass", ast.span) {
+                    self.emit_get("this", ast.span, start_line);
+                    self.emit_get("super", ast.span, start_line);
+                    self.emit_named(OpCode::GetSuper, name, ast.span, start_line);
                 }
-            },
-            AstBody::VarDecl { ident, initializer } => {
+            }
+            AstBody::VarDecl {
+                exported,
+                ident,
+                initializer,
+            } => {
+                self.check_export(*exported, ast.span);
                 match *initializer {
                     Some(initializer) => self.push(initializer, mapper),
                     None => self.builder.push_op(OpCode::Nil, start_line),
                 }
-                self.define_variable(ident, start_line);
+                self.define_variable(ident, *exported, ast.span, start_line);
             }
             AstBody::FunDecl {
+                exported,
                 ident,
                 parameters,
                 body,
             } => {
-                let mut fun_compiler = Compiler::with_parent(parameters, self);
-                for stmt in body.iter() {
-                    fun_compiler.push(*stmt, mapper);
+                self.check_export(*exported, ast.span);
+                if self.declares_global(*exported) {
+                    self.push_function(
+                        FunctionKind::Function,
+                        ident.clone(),
+                        parameters,
+                        body,
+                        ast.span,
+                        mapper,
+                    );
+                    self.define_variable(ident, *exported, ast.span, start_line);
+                } else {
+                    let decls = std::slice::from_ref(&ast);
+                    let first_slot = self.declare_local_functions(decls, mapper);
+                    self.define_local_functions(decls, first_slot, mapper);
                 }
-                // TODO: explicit returnがあるときここは無駄
-                fun_compiler.end_scope(end_line);
-                fun_compiler.emit_return(None, mapper, end_line);
-                let (function, upvalues) = fun_compiler.build(ident.into());
+            }
+            AstBody::Lambda { parameters, body } => {
+                let name = format!(
+                    "lambda_{}_{}",
+                    start_line,
+                    mapper.find_column(ast.span.start)
+                );
+                self.push_function(
+                    FunctionKind::Function,
+                    name,
+                    parameters,
+                    body,
+                    ast.span,
+                    mapper,
+                );
+            }
+            AstBody::ClassDecl {
+                exported,
+                ident,
+                superclass,
+                methods,
+            } => {
+                self.check_export(*exported, ast.span);
+                self.emit_named(OpCode::Class, ident, ast.span, start_line);
+                self.define_variable(ident, *exported, ast.span, start_line);
 
-                let fun_const_index = self.builder.push_constant(Constant::Function(function));
-                self.builder.push_op(OpCode::Constant, start_line);
-                self.builder.push_u8(fun_const_index, start_line);
-
-                let upvalues_len = upvalues.len();
-                if upvalues_len > 0 {
-                    self.builder.push_op(OpCode::Closure, start_line);
-                    self.builder
-                        .push_u8(u8::try_from(upvalues_len).unwrap(), start_line);
-                    for upvalue in upvalues.iter() {
-                        let (is_local, index) = match *upvalue {
-                            Upvalue::InLocal { index } => (1, index),
-                            Upvalue::InUpvalue { index } => (0, index),
+                // The superclass is stored in the local `super` captured by the methods.
+                if let Some(superclass) = superclass {
+                    if matches!(superclass.body, AstBody::Var(name) if name == ident) {
+                        self.errors.push(Simple::custom(
+                            superclass.span.start..superclass.span.end,
+                            "a class cannot inherit from itself",
+                        ));
+                    }
+                    self.begin_scope();
+                    self.push(*superclass, mapper);
+                    self.push_local("super", superclass.span);
+                    self.emit_get(ident, ast.span, start_line);
+                    self.builder.push_op(OpCode::Inherit, start_line);
+                }
+
+                if !methods.is_empty() {
+                    self.emit_get(ident, ast.span, start_line);
+                }
+                for method in methods.iter() {
+                    if let AstBody::FunDecl {
+                        ident: method_name,
+                        parameters,
+                        body,
+                        ..
+                    } = method.body
+                    {
+                        let kind = if method_name == "init" {
+                            FunctionKind::Initializer
+                        } else {
+                            FunctionKind::Method
                         };
-                        self.builder.push_u8(is_local, start_line);
-                        self.builder.push_u8(index, start_line);
+                        self.push_function(
+                            kind,
+                            format!("{}.{}", ident, method_name),
+                            parameters,
+                            body,
+                            method.span,
+                            mapper,
+                        );
+                        self.emit_named(
+                            OpCode::Method,
+                            method_name,
+                            method.span,
+                            mapper.find(method.span.start),
+                        );
                     }
                 }
+                if !methods.is_empty() {
+                    self.builder.push_op(OpCode::Pop, end_line);
+                }
 
-                self.define_variable(ident, start_line);
+                if superclass.is_some() {
+                    self.end_scope(end_line);
+                }
+            }
+            AstBody::GetProperty { object, name } => {
+                self.push(*object, mapper);
+                self.emit_named(OpCode::GetProperty, name, ast.span, start_line);
+            }
+            AstBody::GetIndex { object, index } => {
+                self.push(*object, mapper);
+                self.push(*index, mapper);
+                self.builder.push_op(OpCode::GetIndex, start_line);
+            }
+            AstBody::SetIndex {
+                object,
+                index,
+                value,
+            } => {
+                self.push(*object, mapper);
+                self.push(*index, mapper);
+                self.push(*value, mapper);
+                self.builder.push_op(OpCode::SetIndex, start_line);
+            }
+            AstBody::SetProperty {
+                object,
+                name,
+                value,
+            } => {
+                self.push(*object, mapper);
+                self.push(*value, mapper);
+                self.emit_named(OpCode::SetProperty, name, ast.span, start_line);
             }
             AstBody::Call { callee, arguments } => {
-                self.push(*callee, mapper);
-                for argument in arguments.iter() {
-                    self.push(*argument, mapper);
+                // TODO: handle errors when the arguments are too many
+                let arguments_len = u8::try_from(arguments.len()).unwrap();
+                match callee.body {
+                    // Invoke the method without creating the bound method.
+                    AstBody::GetProperty { object, name } => {
+                        self.push(*object, mapper);
+                        for argument in arguments.iter() {
+                            self.push(*argument, mapper);
+                        }
+                        self.emit_named(OpCode::Invoke, name, ast.span, start_line);
+                        self.builder.push_u8(arguments_len, start_line);
+                    }
+                    AstBody::Super(name) => {
+                        self.check_implicit("super", "super outside of a subclass", callee.span);
+                        self.emit_get("this", ast.span, start_line);
+                        for argument in arguments.iter() {
+                            self.push(*argument, mapper);
+                        }
+                        self.emit_get("super", ast.span, start_line);
+                        self.emit_named(OpCode::SuperInvoke, name, ast.span, start_line);
+                        self.builder.push_u8(arguments_len, start_line);
+                    }
+                    _ => {
+                        self.push(*callee, mapper);
+                        for argument in arguments.iter() {
+                            self.push(*argument, mapper);
+                        }
+                        self.builder.push_op(OpCode::Call, start_line);
+                        self.builder.push_u8(arguments_len, start_line);
+                    }
+                }
+            }
+            AstBody::Reset(expr) => {
+                self.push(*expr, mapper);
+                self.builder.push_op(OpCode::Reset, start_line);
+            }
+            AstBody::Shift(expr) => {
+                self.push(*expr, mapper);
+                self.builder.push_op(OpCode::Shift, start_line);
+            }
+            AstBody::Block(stmts) => self.push_block(stmts, mapper, end_line),
+            AstBody::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.push(*condition, mapper);
+                let then_jump = self.builder.push_jump(OpCode::JumpIfFalse, start_line);
+                self.builder.push_op(OpCode::Pop, start_line);
+                self.push_block(then_branch, mapper, end_line);
+                let else_jump = self.builder.push_jump(OpCode::Jump, end_line);
+
+                self.patch_jump(then_jump, ast.span);
+                self.builder.push_op(OpCode::Pop, start_line);
+                if let Some(else_branch) = else_branch {
+                    self.push_block(else_branch, mapper, end_line);
+                }
+                self.patch_jump(else_jump, ast.span);
+            }
+            AstBody::While { condition, body } => {
+                let start = self.builder.position();
+                self.push(*condition, mapper);
+                let exit_jump = self.builder.push_jump(OpCode::JumpIfFalse, start_line);
+                self.builder.push_op(OpCode::Pop, start_line);
+
+                let breaks = self.push_loop_body(start, body, None, mapper, ast.span);
+
+                self.patch_jump(exit_jump, ast.span);
+                self.builder.push_op(OpCode::Pop, start_line);
+                for break_jump in breaks {
+                    self.patch_jump(break_jump, ast.span);
+                }
+            }
+            AstBody::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                // The variables declared by the initializer live until the end of the loop,
+                // so all the iterations share them unlike the locals declared in the body.
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.push(*initializer, mapper);
+                }
+
+                let start = self.builder.position();
+                let exit_jump = condition.map(|condition| {
+                    self.push(condition, mapper);
+                    let exit_jump = self.builder.push_jump(OpCode::JumpIfFalse, start_line);
+                    self.builder.push_op(OpCode::Pop, start_line);
+                    exit_jump
+                });
+
+                let breaks = self.push_loop_body(start, body, *increment, mapper, ast.span);
+
+                if let Some(exit_jump) = exit_jump {
+                    self.patch_jump(exit_jump, ast.span);
+                    self.builder.push_op(OpCode::Pop, start_line);
+                }
+                for break_jump in breaks {
+                    self.patch_jump(break_jump, ast.span);
+                }
+                self.end_scope(end_line);
+            }
+            AstBody::Break | AstBody::Continue => {
+                let is_break = matches!(ast.body, AstBody::Break);
+                let level = match self.loops.last() {
+                    Some(innermost) => innermost.level,
+                    None => {
+                        let keyword = if is_break { "break" } else { "continue" };
+                        self.errors.push(Simple::custom(
+                            ast.span.start..ast.span.end,
+                            format!("{} outside of a loop", keyword),
+                        ));
+                        return;
+                    }
+                };
+
+                self.discard_locals(level, start_line);
+                let jump = self.builder.push_jump(OpCode::Jump, start_line);
+                let innermost = self.loops.last_mut().unwrap();
+                if is_break {
+                    innermost.breaks.push(jump);
+                } else {
+                    innermost.continues.push(jump);
                 }
-                self.builder.push_op(OpCode::Call, start_line);
-                self.builder
-                    .push_u8(u8::try_from(arguments.len()).unwrap(), start_line);
             }
             AstBody::ExprStmt { expr } => {
                 self.push(*expr, mapper);
@@ -357,10 +1025,22 @@ impl<'parent> Compiler<'parent> {
     }
 }
 
-pub(crate) fn compile(name: String, ast: Ast<'_>, mapper: &LineMapper) -> Function {
-    let mut compiler = Compiler::default();
+pub(crate) fn compile(
+    name: String,
+    ast: Ast<'_>,
+    mapper: &LineMapper,
+    top_level: TopLevel,
+) -> Result<Function, Vec<Simple<char>>> {
+    let mut compiler = Compiler {
+        top_level,
+        ..Compiler::default()
+    };
     compiler.push(ast, mapper);
     // TODO: ここにend_scopeが必要なのが気に食わない
     compiler.end_scope(mapper.find(ast.span.end));
-    compiler.build(name).0
+    if compiler.errors.is_empty() {
+        Ok(compiler.build(name).0)
+    } else {
+        Err(compiler.errors)
+    }
 }
@@ -6,14 +6,17 @@ use crate::opcode::Chunk;
 #[derive(Clone)]
 pub(crate) struct Function {
     pub(crate) name: String,
+    /// The number of parameters.
+    pub(crate) arity: usize,
     pub(crate) chunk: Rc<Chunk>,
     pub(crate) upvalues: usize,
 }
 
 impl Function {
-    pub(crate) fn new(name: String, chunk: Rc<Chunk>, upvalues: usize) -> Self {
+    pub(crate) fn new(name: String, arity: usize, chunk: Rc<Chunk>, upvalues: usize) -> Self {
         Self {
             name,
+            arity,
             chunk,
             upvalues,
         }
@@ -35,4 +38,14 @@ impl Constant {
             Constant::Function(f) => format!("<function {}>", f.name),
         }
     }
+
+    /// Whether the constants can share a slot of the chunk. Functions are never shared.
+    pub(crate) fn is_same(&self, other: &Constant) -> bool {
+        match (self, other) {
+            // Compare the bits so that 0 and -0 are kept apart.
+            (Constant::Number(a), Constant::Number(b)) => a.to_bits() == b.to_bits(),
+            (Constant::String(a), Constant::String(b)) => a == b,
+            _ => false,
+        }
+    }
 }
@@ -0,0 +1,69 @@
+use std::io::{self, Write};
+
+use ariadne::{Config, Label, Report, ReportKind, Source};
+use chumsky::{error::SimpleReason, prelude::Simple};
+
+fn describe(c: Option<&char>) -> String {
+    match c {
+        Some(c) => format!("{:?}", c),
+        None => "end of input".into(),
+    }
+}
+
+/// Render a parse error as a labelled snippet of the source.
+pub(crate) fn write_parse_error(
+    file_name: &str,
+    source: &str,
+    error: &Simple<char>,
+    color: bool,
+    writer: &mut dyn Write,
+) -> io::Result<()> {
+    let span = error.span();
+    let mut report = Report::build(ReportKind::Error, file_name, span.start)
+        .with_config(Config::default().with_color(color));
+
+    match error.reason() {
+        SimpleReason::Custom(message) => {
+            report = report
+                .with_message(message)
+                .with_label(Label::new((file_name, span)).with_message(message));
+        }
+        SimpleReason::Unclosed {
+            span: open,
+            delimiter,
+        } => {
+            report = report
+                .with_message(format!("unclosed delimiter {:?}", delimiter))
+                .with_label(
+                    Label::new((file_name, open.clone()))
+                        .with_message(format!("the delimiter {:?} is opened here", delimiter)),
+                )
+                .with_label(
+                    Label::new((file_name, span))
+                        .with_message(format!("found {}", describe(error.found()))),
+                );
+        }
+        SimpleReason::Unexpected => {
+            report = report
+                .with_message(match error.found() {
+                    Some(_) => "unexpected input",
+                    None => "unexpected end of input",
+                })
+                .with_label(
+                    Label::new((file_name, span))
+                        .with_message(format!("found {}", describe(error.found()))),
+                );
+        }
+    }
+
+    // The expected tokens are stored in a hash set, so sort them for stable output.
+    let mut expected: Vec<String> = error.expected().map(|c| describe(c.as_ref())).collect();
+    expected.sort();
+    if !expected.is_empty() {
+        report = report.with_note(format!("expected one of {}", expected.join(", ")));
+    }
+
+    report
+        .finish()
+        .write((file_name, Source::from(source)), writer)
+}
@@ -2,7 +2,7 @@ use chumsky::Parser;
 use typed_arena::Arena;
 
 use crate::{
-    compiler,
+    compiler::{self, TopLevel},
     parser::{self, LineMapper},
     side_effect::SideEffectHandler,
     vm::Vm,
@@ -12,6 +12,7 @@ pub(crate) struct Driver<'handler> {
     pub(crate) file_name: String,
     pub(crate) source: String,
     pub(crate) run: bool,
+    pub(crate) top_level: TopLevel,
     pub(crate) handler: &'handler mut (dyn SideEffectHandler + 'handler),
 }
 
@@ -20,21 +21,24 @@ impl Driver<'_> {
         let arena = Arena::new();
         let parser = parser::parser(&arena);
         let mapper = LineMapper::new(&self.source);
-        match parser.parse(self.source.as_str()) {
-            Ok(ast) => {
-                let compiled =
-                    compiler::compile(format!("{}_initial_code", self.file_name), ast, &mapper);
-
+        let compiled = parser.parse(self.source.as_str()).and_then(|ast| {
+            compiler::compile(
+                format!("{}_initial_code", self.file_name),
+                ast,
+                &mapper,
+                self.top_level,
+            )
+        });
+        match compiled {
+            Ok(compiled) => {
                 if self.run {
                     let mut vm = Vm::initial(compiled, self.handler);
-                    while !vm.done() {
-                        vm.step();
-                    }
+                    vm.run();
                 }
             }
             Err(errors) => self
                 .handler
-                .compile_error(&self.file_name, errors, &mapper)
+                .compile_error(&self.file_name, &self.source, errors)
                 .unwrap(),
         }
     }
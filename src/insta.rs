@@ -5,7 +5,10 @@ use std::io::{self, Write};
 
 use chumsky::prelude::Simple;
 
-use crate::{driver::Driver, parser::LineMapper, side_effect::SideEffectHandler};
+use crate::{
+    compiler::TopLevel, diagnostic, driver::Driver, side_effect::SideEffectHandler,
+    vm::RuntimeError,
+};
 
 struct InstaCapturingHandler {
     test_name: String,
@@ -16,14 +19,26 @@ impl SideEffectHandler for InstaCapturingHandler {
     fn compile_error(
         &mut self,
         file_name: &str,
+        source: &str,
         errors: Vec<Simple<char>>,
-        _mapper: &LineMapper,
     ) -> io::Result<()> {
-        let error_messages: Vec<String> = errors.iter().map(Simple::<char>::to_string).collect();
+        let mut rendered = vec![];
+        for error in errors.iter() {
+            diagnostic::write_parse_error(file_name, source, error, false, &mut rendered)?;
+        }
+
+        insta::assert_snapshot!(
+            format!("{}_compile_error", self.test_name),
+            String::from_utf8_lossy(&rendered)
+        );
 
-        insta::assert_yaml_snapshot!(
-            format!("{}_{}_error_messages", self.test_name, file_name),
-            error_messages
+        Ok(())
+    }
+
+    fn runtime_error(&mut self, error: &RuntimeError) -> io::Result<()> {
+        insta::assert_snapshot!(
+            format!("{}_runtime_error", self.test_name),
+            error.to_string()
         );
 
         Ok(())
@@ -56,11 +71,16 @@ impl InstaCapturingHandler {
 }
 
 fn run_test(test_name: &str, source: &str) {
+    run_test_with_top_level(test_name, source, TopLevel::Global);
+}
+
+fn run_test_with_top_level(test_name: &str, source: &str, top_level: TopLevel) {
     let mut handler = InstaCapturingHandler::new(test_name);
     let mut driver = Driver {
         file_name: test_name.into(),
         source: source.into(),
         run: true,
+        top_level,
         handler: &mut handler,
     };
 
@@ -209,3 +229,1738 @@ print(cls());
 "#,
     );
 }
+
+#[test]
+fn test_callcc_return() {
+    run_test(
+        "test_callcc_return",
+        r#"
+fun ignore(k) {
+    return "returned";
+}
+
+print(callcc(ignore));
+"#,
+    );
+}
+
+#[test]
+fn test_callcc_escape() {
+    run_test(
+        "test_callcc_escape",
+        r#"
+fun inner(k) {
+    print("inner");
+    k("escaped");
+    print("unreachable");
+}
+
+fun outer(k) {
+    inner(k);
+    print("unreachable");
+    return "returned";
+}
+
+print(callcc(outer));
+print("done");
+"#,
+    );
+}
+
+#[test]
+fn test_callcc_escape_closes_upvalues() {
+    run_test(
+        "test_callcc_escape_closes_upvalues",
+        r#"
+fun main() {
+    var outer = "outer";
+    fun get_outer() {
+        return outer;
+    }
+
+    fun escape(k) {
+        var inner = "inner";
+        fun get_inner() {
+            return inner;
+        }
+        k(get_inner);
+    }
+
+    var get_inner = callcc(escape);
+    outer = "outer modified";
+    print(get_inner());
+    print(get_outer());
+}
+
+main();
+"#,
+    );
+}
+
+#[test]
+fn test_reset_shift() {
+    run_test(
+        "test_reset_shift",
+        r#"
+fun twice(k) {
+    return k(k(10));
+}
+
+fun body() {
+    return 1 + shift(twice);
+}
+
+print(reset(body));
+"#,
+    );
+}
+
+#[test]
+fn test_shift_abort() {
+    run_test(
+        "test_shift_abort",
+        r#"
+fun abort(k) {
+    return "aborted";
+}
+
+fun body() {
+    print("before");
+    shift(abort);
+    print("unreachable");
+    return "returned";
+}
+
+print(reset(body));
+print("done");
+"#,
+    );
+}
+
+#[test]
+fn test_shift_nested_call() {
+    run_test(
+        "test_shift_nested_call",
+        r#"
+fun both(k) {
+    return k(5) + k(10);
+}
+
+fun inner() {
+    return shift(both) * 2;
+}
+
+fun body() {
+    var offset = 1;
+    return inner() + offset;
+}
+
+print(reset(body));
+"#,
+    );
+}
+
+#[test]
+fn test_shift_shared_capture() {
+    run_test(
+        "test_shift_shared_capture",
+        r#"
+fun twice(k) {
+    print(k(0));
+    return k(0);
+}
+
+fun body() {
+    var count = 0;
+    fun incr() {
+        count = count + 1;
+        return count;
+    }
+    var ignored = shift(twice);
+    return incr();
+}
+
+print(reset(body));
+"#,
+    );
+}
+
+#[test]
+fn test_callcc_multi_shot() {
+    run_test(
+        "test_callcc_multi_shot",
+        r#"
+var resume;
+var done;
+
+fun capture(k) {
+    resume = k;
+    return "first";
+}
+
+fun main() {
+    var local = "local";
+    var count = 0;
+    fun incr() {
+        count = count + 1;
+        return count;
+    }
+
+    var x = callcc(capture);
+    print(local);
+    local = "modified";
+    print(incr());
+    done(x);
+}
+
+fun run(k) {
+    done = k;
+    main();
+}
+
+fun rerun_second(k) {
+    done = k;
+    resume("second");
+}
+
+fun rerun_third(k) {
+    done = k;
+    resume("third");
+}
+
+print(callcc(run));
+print(callcc(rerun_second));
+print(callcc(rerun_third));
+"#,
+    );
+}
+
+#[test]
+fn test_callcc_multi_shot_uncaptured_local() {
+    run_test(
+        "test_callcc_multi_shot_uncaptured_local",
+        r#"
+fun f() {
+    var n = 0;
+    var k;
+    var r = callcc(fun (c) {
+        k = c;
+        return 0;
+    });
+    n = n + 1;
+    print(n);
+    if (n < 3) {
+        k(n);
+    }
+}
+
+f();
+"#,
+    );
+}
+
+#[test]
+fn test_callcc_multi_shot_shared_locals() {
+    run_test(
+        "test_callcc_multi_shot_shared_locals",
+        r#"
+var k;
+
+fun main() {
+    var trail = "";
+    var x = callcc(fun (c) {
+        k = c;
+        return 1;
+    });
+    // Each resumption sees the trail left by the previous ones.
+    trail = trail + x;
+    print(trail);
+    if (x < 3) {
+        k(x + 1);
+    }
+}
+
+main();
+"#,
+    );
+}
+
+#[test]
+fn test_callcc_multi_shot_temporaries() {
+    run_test(
+        "test_callcc_multi_shot_temporaries",
+        r#"
+var k;
+var count = 0;
+
+fun add(x, y) {
+    x = x + y;
+    return x;
+}
+
+print(add(10, callcc(fun (c) {
+    k = c;
+    return 1;
+})));
+count = count + 1;
+if (count < 3) {
+    k(count * 100);
+}
+"#,
+    );
+}
+
+#[test]
+fn test_callcc_resume_nested_frames() {
+    run_test(
+        "test_callcc_resume_nested_frames",
+        r#"
+var resume;
+var done;
+
+fun capture(k) {
+    resume = k;
+    return 1;
+}
+
+fun inner() {
+    var a = 100;
+    return a + callcc(capture);
+}
+
+fun middle() {
+    var b = 10;
+    fun bump() {
+        b = b + 1;
+        return b;
+    }
+    var result = inner() + bump();
+    return result;
+}
+
+fun run(k) {
+    done = k;
+    var result = middle();
+    done(result);
+}
+
+fun rerun(k) {
+    done = k;
+    resume(2);
+}
+
+print(callcc(run));
+print(callcc(rerun));
+print(callcc(rerun));
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_bad_operand() {
+    run_test(
+        "test_runtime_error_bad_operand",
+        r#"
+print("before");
+print(1 - "one");
+print("after");
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_undefined_variable() {
+    run_test(
+        "test_runtime_error_undefined_variable",
+        r#"
+fun foo() {
+    return undefined;
+}
+
+print(foo());
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_not_callable() {
+    run_test(
+        "test_runtime_error_not_callable",
+        r#"
+var number = 1;
+number();
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_arity() {
+    run_test(
+        "test_runtime_error_arity",
+        r#"
+fun add(a, b) {
+    return a + b;
+}
+
+print(add(1, 2));
+print(add(1));
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_arity_one_argument() {
+    run_test(
+        "test_runtime_error_arity_one_argument",
+        r#"
+fun id(x) {
+    return x;
+}
+
+print(id(1));
+print(id(1, 2));
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_shift_outside_reset() {
+    run_test(
+        "test_runtime_error_shift_outside_reset",
+        r#"
+fun f(k) {
+    return k;
+}
+
+shift(f);
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_callcc_builtin() {
+    run_test(
+        "test_runtime_error_callcc_builtin",
+        r#"
+callcc(len);
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_callcc_class() {
+    run_test(
+        "test_runtime_error_callcc_class",
+        r#"
+class Point {}
+callcc(Point);
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_reset_continuation() {
+    run_test(
+        "test_runtime_error_reset_continuation",
+        r#"
+fun capture(k) {
+    return k;
+}
+
+var k = callcc(capture);
+if (k != nil) {
+    reset(k);
+}
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_reset_stack_trace() {
+    run_test(
+        "test_runtime_error_reset_stack_trace",
+        r#"
+fun bad_reset() {
+    return reset(3);
+}
+
+fun outer() {
+    return bad_reset();
+}
+
+outer();
+"#,
+    );
+}
+
+#[test]
+fn test_runtime_error_stack_trace() {
+    run_test(
+        "test_runtime_error_stack_trace",
+        r#"
+fun inner(x) {
+    return x - "one";
+}
+
+fun middle(x) {
+    return inner(x) * 2;
+}
+
+fun outer() {
+    return middle(1);
+}
+
+fun under_reset() {
+    return outer();
+}
+
+reset(under_reset);
+"#,
+    );
+}
+
+#[test]
+fn test_parse_error_unexpected() {
+    run_test(
+        "test_parse_error_unexpected",
+        r#"
+var x = 1;
+print(x +);
+"#,
+    );
+}
+
+#[test]
+fn test_parse_error_unclosed() {
+    run_test(
+        "test_parse_error_unclosed",
+        r#"
+fun foo() {
+    print("foo");
+"#,
+    );
+}
+
+#[test]
+fn test_parse_error_reserved_keyword() {
+    run_test(
+        "test_parse_error_reserved_keyword",
+        r#"
+var return = 1;
+"#,
+    );
+}
+
+#[test]
+fn test_if_else() {
+    run_test(
+        "test_if_else",
+        r#"
+var unset;
+
+if (1) {
+    print("then");
+}
+
+if (unset) {
+    print("unreachable");
+} else {
+    print("else");
+}
+
+if (unset) {
+    print("unreachable");
+} else if ("string") {
+    print("else if");
+} else {
+    print("unreachable");
+}
+
+fun choose(flag) {
+    if (flag) {
+        var local = "local in then";
+        print(local);
+    } else {
+        var local = "local in else";
+        print(local);
+    }
+    return flag;
+}
+
+choose(0);
+choose(unset);
+"#,
+    );
+}
+
+#[test]
+fn test_while() {
+    run_test(
+        "test_while",
+        r#"
+fun countdown() {
+    var a = 3;
+    var b = 2;
+    var c = 1;
+    var d;
+    while (a) {
+        var current = a;
+        print(current);
+        a = b;
+        b = c;
+        c = d;
+    }
+    print("done");
+}
+
+countdown();
+"#,
+    );
+}
+
+#[test]
+fn test_for_body_locals_per_iteration() {
+    run_test(
+        "test_for_body_locals_per_iteration",
+        r#"
+fun closures() {
+    var first;
+    var second;
+    var first_i;
+    var next = 1;
+    var last;
+    for (var i = 10; next; i = i + 1) {
+        var captured = i;
+        fun get() {
+            return captured;
+        }
+        fun get_i() {
+            return i;
+        }
+        if (first) {
+            second = get;
+            next = last;
+        } else {
+            first = get;
+            first_i = get_i;
+        }
+    }
+    print(first());
+    print(second());
+    // The loop variable is shared by all the iterations.
+    print(first_i());
+}
+
+closures();
+"#,
+    );
+}
+
+#[test]
+fn test_break_continue() {
+    run_test(
+        "test_break_continue",
+        r#"
+fun control() {
+    var skip = 1;
+    var stop;
+    for (var i = 0; ; i = i + 1) {
+        var local = i;
+        fun get() {
+            return local;
+        }
+        if (skip) {
+            skip = stop;
+            print("continue");
+            continue;
+        }
+        print(get());
+        if (stop) {
+            break;
+        }
+        stop = 1;
+    }
+    print("after");
+}
+
+control();
+"#,
+    );
+}
+
+#[test]
+fn test_break_outside_loop() {
+    run_test(
+        "test_break_outside_loop",
+        r#"
+fun foo() {
+    while (1) {
+        fun bar() {
+            break;
+        }
+    }
+}
+continue;
+"#,
+    );
+}
+
+#[test]
+fn test_comparison() {
+    run_test(
+        "test_comparison",
+        r#"
+print(1 < 2);
+print(2 <= 2);
+print(1 > 2);
+print(3 >= 2);
+print("abc" < "abd");
+print((1 + 2) * 3 == 9);
+print(1 + 2 != 3);
+
+var nan = 0 / 0;
+print(nan == nan);
+print(nan != nan);
+print(nan < 1);
+print(nan >= 1);
+"#,
+    );
+}
+
+#[test]
+fn test_equality() {
+    run_test(
+        "test_equality",
+        r#"
+var unset;
+var other;
+print(unset == other);
+print(1 == "1");
+print("foo" == "foo");
+print(unset == 0);
+
+fun make() {
+    var captured = 1;
+    fun get() {
+        return captured;
+    }
+    return get;
+}
+
+var first = make();
+var second = make();
+print(first == first);
+print(first == second);
+print(make == make);
+print(callcc == callcc);
+"#,
+    );
+}
+
+#[test]
+fn test_comparison_type_error() {
+    run_test(
+        "test_comparison_type_error",
+        r#"
+print(1 < "2");
+"#,
+    );
+}
+
+#[test]
+fn test_unary() {
+    run_test(
+        "test_unary",
+        r#"
+var unset;
+print(-5);
+print(-(1 + 2) * 3);
+print(2 - -1);
+print(!unset);
+print(!!0);
+print(!1 == 2);
+"#,
+    );
+}
+
+#[test]
+fn test_short_circuit() {
+    run_test(
+        "test_short_circuit",
+        r#"
+var unset;
+
+fun side_effect(value) {
+    print("evaluated");
+    return value;
+}
+
+print(unset and side_effect(1));
+print(1 and side_effect(2));
+print(1 or side_effect(3));
+print(unset or side_effect(4));
+print(unset or 1 and 2);
+print(1 < 2 and 2 < 3);
+"#,
+    );
+}
+
+#[test]
+fn test_negate_type_error() {
+    run_test(
+        "test_negate_type_error",
+        r#"
+print(-"string");
+"#,
+    );
+}
+
+#[test]
+fn test_literals() {
+    run_test(
+        "test_literals",
+        r#"
+print(nil);
+print(true);
+print(false);
+print(nil == nil);
+print(true != false);
+print(!nil);
+
+var flag = true;
+if (flag) {
+    print("flag is true");
+}
+
+var nothing = nil;
+print(nothing == nil);
+"#,
+    );
+}
+
+#[test]
+fn test_number_literals() {
+    run_test(
+        "test_number_literals",
+        r#"
+print(3.14);
+print(0.5);
+print(1e9);
+print(2.5E-3);
+print(1_000_000);
+print(0xff);
+print(0xDEAD_BEEF);
+print(0b1010);
+print(1.5 + 0x10);
+"#,
+    );
+}
+
+#[test]
+fn test_number_literal_out_of_range() {
+    run_test(
+        "test_number_literal_out_of_range",
+        r#"
+print(1e999);
+"#,
+    );
+}
+
+#[test]
+fn test_number_literal_invalid_digit() {
+    run_test(
+        "test_number_literal_invalid_digit",
+        r#"
+print(0b102);
+"#,
+    );
+}
+
+#[test]
+fn test_number_literal_too_large() {
+    run_test(
+        "test_number_literal_too_large",
+        r#"
+print(0x1_0000_0000_0000_0000);
+"#,
+    );
+}
+
+#[test]
+fn test_number_literal_misplaced_separator() {
+    run_test(
+        "test_number_literal_misplaced_separator",
+        r#"
+print(1__000);
+"#,
+    );
+}
+
+#[test]
+fn test_string_escapes() {
+    run_test(
+        "test_string_escapes",
+        r#"
+print("say \"hello\"");
+print("tab\there");
+print("line\nbreak");
+print("back\\slash");
+print("\u{3042}\u{1F600}");
+"#,
+    );
+}
+
+#[test]
+fn test_string_triple_quoted() {
+    run_test(
+        "test_string_triple_quoted",
+        r#"
+print("""first line
+second "line" with \n kept as is""");
+print("""""");
+"#,
+    );
+}
+
+#[test]
+fn test_string_invalid_escape() {
+    run_test(
+        "test_string_invalid_escape",
+        r#"
+print("bad \q escape");
+print("bad \u{110000} code point");
+"#,
+    );
+}
+
+#[test]
+fn test_string_concatenation() {
+    run_test(
+        "test_string_concatenation",
+        r#"
+var n = 3;
+print("count: " + n);
+print(1.5 + " apples");
+print("is " + true + ", not " + nil);
+print("foo" + "bar");
+"#,
+    );
+}
+
+#[test]
+fn test_string_concatenation_type_error() {
+    run_test(
+        "test_string_concatenation_type_error",
+        r#"
+fun f() {}
+print("f is " + f);
+"#,
+    );
+}
+
+#[test]
+fn test_string_interpolation() {
+    run_test(
+        "test_string_interpolation",
+        r#"
+var name = "world";
+var n = 2;
+print("hello ${name}!");
+print("${n} + ${n} = ${n + n}");
+print("nested ${"inner ${name}"}");
+print("${nil} ${false} costs $5 \${not interpolated}");
+"#,
+    );
+}
+
+#[test]
+fn test_print_vs_string_conversion() {
+    run_test(
+        "test_print_vs_string_conversion",
+        r#"
+print(nil);
+print("${nil}");
+print(true);
+print("" + true);
+print("nil");
+print(1.5);
+print("${1.5}");
+"#,
+    );
+}
+
+#[test]
+fn test_string_interpolation_type_error() {
+    run_test(
+        "test_string_interpolation_type_error",
+        r#"
+fun f() {}
+print("f is ${f}");
+"#,
+    );
+}
+
+#[test]
+fn test_comments() {
+    run_test(
+        "test_comments",
+        r#"
+// A line comment at the start.
+var x = 1; // A trailing comment.
+/* A block comment
+   spanning lines /* with a nested one */
+   and more. */
+fun add(a, /* inline */ b) {
+    // print("disabled");
+    return a + b; /* trailing */
+}
+print(add(x, 2) /* ** // */);
+print(x / 2); // Division is not a comment.
+// A comment at the end without a newline."#,
+    );
+}
+
+#[test]
+fn test_comments_line_numbers() {
+    run_test(
+        "test_comments_line_numbers",
+        r#"
+/*
+ * A block comment pushing the code down.
+ */
+// And a line comment.
+fun fail() {
+    return 1 - "one";
+}
+fail();
+"#,
+    );
+}
+
+#[test]
+fn test_comments_unclosed() {
+    run_test(
+        "test_comments_unclosed",
+        r#"
+print(1);
+/* outer /* inner */
+print(2);
+"#,
+    );
+}
+
+#[test]
+fn test_block_scope() {
+    run_test(
+        "test_block_scope",
+        r#"
+var x = "global";
+{
+    var x = "outer";
+    {
+        var x = "inner";
+        print(x);
+    }
+    print(x);
+    { }
+}
+print(x);
+"#,
+    );
+}
+
+#[test]
+fn test_block_closes_captured_locals() {
+    run_test(
+        "test_block_closes_captured_locals",
+        r#"
+var closures = nil;
+fun make() {
+    var getters = nil;
+    {
+        var a = "first";
+        fun get_a() {
+            return a;
+        }
+        getters = get_a;
+    }
+    {
+        var b = "second";
+        fun get_b() {
+            return b;
+        }
+        print(getters());
+        print(get_b());
+    }
+}
+make();
+
+{
+    var counter = 0;
+    fun increment() {
+        counter = counter + 1;
+        return counter;
+    }
+    closures = increment;
+}
+print(closures());
+print(closures());
+"#,
+    );
+}
+
+#[test]
+fn test_top_level_private() {
+    run_test_with_top_level(
+        "test_top_level_private",
+        r#"
+var counter = 0;
+fun increment() {
+    counter = counter + 1;
+    return counter;
+}
+increment();
+print(increment());
+print(counter);
+
+export var exported = "exported";
+export fun read_exported() {
+    return exported;
+}
+print(read_exported());
+
+fun read_global() {
+    return undefined_global;
+}
+print(read_global());
+"#,
+        TopLevel::Private,
+    );
+}
+
+#[test]
+fn test_export_outside_top_level() {
+    run_test(
+        "test_export_outside_top_level",
+        r#"
+fun f() {
+    export var x = 1;
+}
+{
+    export fun g() {}
+}
+"#,
+    );
+}
+
+#[test]
+fn test_recursive_local_function() {
+    run_test(
+        "test_recursive_local_function",
+        r#"
+fun outer() {
+    fun fib(n) {
+        if (n < 2) {
+            return n;
+        }
+        return fib(n - 1) + fib(n - 2);
+    }
+    return fib;
+}
+var fib = outer();
+print(fib(10));
+"#,
+    );
+}
+
+#[test]
+fn test_mutually_recursive_local_functions() {
+    run_test(
+        "test_mutually_recursive_local_functions",
+        r#"
+{
+    fun is_even(n) {
+        if (n == 0) {
+            return true;
+        }
+        return is_odd(n - 1);
+    }
+    fun is_odd(n) {
+        if (n == 0) {
+            return false;
+        }
+        return is_even(n - 1);
+    }
+    print(is_even(10));
+    print(is_odd(7));
+    print(is_even(3));
+}
+"#,
+    );
+}
+
+#[test]
+fn test_recursive_private_top_level_function() {
+    run_test_with_top_level(
+        "test_recursive_private_top_level_function",
+        r#"
+fun countdown(n) {
+    if (n > 0) {
+        print(n);
+        countdown(n - 1);
+    }
+}
+countdown(3);
+"#,
+        TopLevel::Private,
+    );
+}
+
+#[test]
+fn test_private_top_level_forward_reference() {
+    run_test_with_top_level(
+        "test_private_top_level_forward_reference",
+        r#"
+fun a() {
+    return b();
+}
+var x = 1;
+fun b() {
+    return x;
+}
+print(a());
+"#,
+        TopLevel::Private,
+    );
+}
+
+#[test]
+fn test_lambda() {
+    run_test(
+        "test_lambda",
+        r#"
+fun apply(f, x) {
+    return f(x);
+}
+print(apply(fun (x) { return x * 2; }, 21));
+print(apply((x) => x + 1, 41));
+
+var offset = 100;
+fun make_adder(n) {
+    return (x) => x + n + offset;
+}
+var add = make_adder(10);
+print(add(1));
+
+var greet = () => "hello";
+print(greet());
+"#,
+    );
+}
+
+#[test]
+fn test_lambdas_on_same_line() {
+    run_test(
+        "test_lambdas_on_same_line",
+        r#"
+var add = (x) => x + 1; var double = (x) => x * 2;
+print(add(1));
+print(double(5));
+"#,
+    );
+}
+
+#[test]
+fn test_lambda_callcc() {
+    run_test(
+        "test_lambda_callcc",
+        r#"
+print(callcc(fun (k) {
+    k(1);
+    print("unreachable");
+}));
+"#,
+    );
+}
+
+#[test]
+fn test_class_fields() {
+    run_test(
+        "test_class_fields",
+        r#"
+class Point {}
+var p = Point();
+p.x = 1;
+p.y = 2;
+print(p.x + p.y);
+p.x = p.x + 10;
+print(p.x);
+print(p);
+print(Point);
+
+fun make_node(value) {
+    class Node {}
+    var node = Node();
+    node.value = value;
+    node.next = nil;
+    return node;
+}
+var list = make_node(1);
+list.next = make_node(2);
+list.next.next = make_node(3);
+print(list.next.next.value);
+print(p == p);
+print(p == Point());
+"#,
+    );
+}
+
+#[test]
+fn test_class_undefined_property() {
+    run_test(
+        "test_class_undefined_property",
+        r#"
+class Empty {}
+var e = Empty();
+print(e.missing);
+"#,
+    );
+}
+
+#[test]
+fn test_class_property_of_non_instance() {
+    run_test(
+        "test_class_property_of_non_instance",
+        r#"
+var n = 1;
+n.field = 2;
+"#,
+    );
+}
+
+#[test]
+fn test_invalid_assignment_target() {
+    run_test(
+        "test_invalid_assignment_target",
+        r#"
+f() = 1;
+"#,
+    );
+}
+
+#[test]
+fn test_class_methods() {
+    run_test(
+        "test_class_methods",
+        r#"
+class Counter {
+    init(start) {
+        this.count = start;
+    }
+
+    increment() {
+        this.count = this.count + 1;
+        return this;
+    }
+}
+
+var counter = Counter(10);
+print(counter.increment().increment().count);
+var increment = counter.increment;
+increment();
+print(counter.count);
+print(increment);
+print(counter.init(0) == counter);
+"#,
+    );
+}
+
+#[test]
+fn test_class_method_continuations() {
+    run_test(
+        "test_class_method_continuations",
+        r#"
+class Counter {
+    init(start) {
+        this.count = start;
+    }
+
+    escape(k) {
+        k(this.count);
+        print("unreachable");
+    }
+
+    twice(k) {
+        return k(k(this.count));
+    }
+
+    body() {
+        return 1 + shift(this.twice);
+    }
+}
+
+var counter = Counter(10);
+print(callcc(counter.escape));
+print(reset(counter.body));
+"#,
+    );
+}
+
+#[test]
+fn test_class_this_in_closure() {
+    run_test(
+        "test_class_this_in_closure",
+        r#"
+class Greeter {
+    init(name) {
+        this.name = name;
+    }
+
+    greeter() {
+        return () => "hello, ${this.name}";
+    }
+}
+
+var greet = Greeter("world").greeter();
+print(greet());
+"#,
+    );
+}
+
+#[test]
+fn test_class_field_shadows_method() {
+    run_test(
+        "test_class_field_shadows_method",
+        r#"
+class Box {
+    get() {
+        return "method";
+    }
+}
+
+var b = Box();
+b.get = () => "field";
+print(b.get());
+"#,
+    );
+}
+
+#[test]
+fn test_class_inheritance() {
+    run_test(
+        "test_class_inheritance",
+        r#"
+class Animal {
+    init(name) {
+        this.name = name;
+    }
+
+    speak() {
+        return "${this.name} makes a sound";
+    }
+
+    describe() {
+        return "an animal: " + this.speak();
+    }
+}
+
+class Dog < Animal {
+    speak() {
+        return super.speak() + " (woof)";
+    }
+
+    parent() {
+        return super.speak;
+    }
+}
+
+var dog = Dog("rex");
+print(dog.speak());
+print(dog.describe());
+print(dog.parent()());
+"#,
+    );
+}
+
+#[test]
+fn test_class_init_arity() {
+    run_test(
+        "test_class_init_arity",
+        r#"
+class Point {
+    init(x, y) {
+        this.x = x;
+        this.y = y;
+    }
+}
+
+Point(1);
+"#,
+    );
+}
+
+#[test]
+fn test_class_inherit_non_class() {
+    run_test(
+        "test_class_inherit_non_class",
+        r#"
+var NotClass = 1;
+class A < NotClass {}
+"#,
+    );
+}
+
+#[test]
+fn test_class_compile_errors() {
+    run_test(
+        "test_class_compile_errors",
+        r#"
+print(this);
+
+class A < A {
+    init() {
+        return 1;
+    }
+
+    method() {
+        return super.method();
+    }
+}
+
+fun f() {
+    return super.f;
+}
+"#,
+    );
+}
+
+#[test]
+fn test_list() {
+    run_test(
+        "test_list",
+        r#"
+var xs = [1, "two", nil,];
+print(xs);
+print(xs[1]);
+xs[2] = 3;
+print(len(xs));
+
+var ys = xs;
+push(ys, [4, 5]);
+print(xs);
+print(xs[3][0]);
+print(pop(xs));
+print(ys);
+print(xs == ys);
+print([] == []);
+print(len("héllo"));
+"#,
+    );
+}
+
+#[test]
+fn test_list_in_closure() {
+    run_test(
+        "test_list_in_closure",
+        r#"
+var xs = [];
+for (var i = 0; i < 3; i = i + 1) {
+    push(xs, () => i);
+}
+print(len(xs));
+print(xs[0]());
+"#,
+    );
+}
+
+#[test]
+fn test_list_out_of_bounds() {
+    run_test(
+        "test_list_out_of_bounds",
+        r#"
+var xs = [1, 2, 3];
+print(xs[2]);
+print(xs[3]);
+"#,
+    );
+}
+
+#[test]
+fn test_list_index_errors() {
+    run_test(
+        "test_list_index_errors",
+        r#"
+var xs = [1, 2, 3];
+xs[1.5] = 0;
+"#,
+    );
+}
+
+#[test]
+fn test_list_pop_empty() {
+    run_test(
+        "test_list_pop_empty",
+        r#"
+var xs = [];
+pop(xs);
+"#,
+    );
+}
+
+#[test]
+fn test_list_containing_itself() {
+    run_test(
+        "test_list_containing_itself",
+        r#"
+var xs = [1];
+push(xs, xs);
+print(xs);
+var ys = [xs, [xs]];
+print(ys);
+print([ys, ys]);
+"#,
+    );
+}
+
+#[test]
+fn test_map() {
+    run_test(
+        "test_map",
+        r#"
+var config = {
+    "name": "tuzuku",
+    "version": 1,
+    2: "two",
+    nil: "nothing",
+};
+print(config);
+print(config["name"]);
+config["version"] = config["version"] + 1;
+config["new"] = true;
+print(len(config));
+
+var alias = config;
+print(delete(alias, "name"));
+print(delete(alias, "name"));
+print(has(config, "name"));
+print(has(config, "new"));
+
+var ks = keys(config);
+for (var i = 0; i < len(ks); i = i + 1) {
+    print("${ks[i]} = ${config[ks[i]]}");
+}
+print({} == {});
+"#,
+    );
+}
+
+#[test]
+fn test_map_keys() {
+    run_test(
+        "test_map_keys",
+        r#"
+class Key {}
+var a = Key();
+var b = Key();
+var m = {a: "a", b: "b", -0: "zero", 1 / 2: "half"};
+print(m[a]);
+print(m[b]);
+print(m[0]);
+print(m[0.5]);
+print(m[Key()]);
+"#,
+    );
+}
+
+#[test]
+fn test_map_reference_key_order() {
+    run_test(
+        "test_map_reference_key_order",
+        r#"
+class Key {}
+var a = Key();
+var b = Key();
+var c = Key();
+var m = {c: "c", "x": 0, a: "a", [2]: "two"};
+m[b] = "b";
+m[[1]] = "one";
+m[c] = "c again";
+m[false] = 1;
+print(m);
+delete(m, a);
+m[a] = "a again";
+var ks = keys(m);
+for (var i = 0; i < len(ks); i = i + 1) {
+    print(m[ks[i]]);
+}
+"#,
+    );
+}
+
+#[test]
+fn test_map_nan_key() {
+    run_test(
+        "test_map_nan_key",
+        r#"
+var m = {};
+m[0 / 0] = 1;
+"#,
+    );
+}
+
+#[test]
+fn test_map_block_statement() {
+    run_test(
+        "test_map_block_statement",
+        r#"
+{}
+{ print({"key": "value"}["key"]); }
+{"key": "value"};
+"#,
+    );
+}
+
+#[test]
+fn test_map_containing_itself() {
+    run_test(
+        "test_map_containing_itself",
+        r#"
+var m = {};
+m["self"] = m;
+print(m);
+var xs = [m];
+m[xs] = xs;
+print(m);
+"#,
+    );
+}
+
+#[test]
+fn test_too_many_locals() {
+    let declarations: String = (0..300).map(|i| format!("var v{} = nil;\n", i)).collect();
+    run_test_with_top_level(
+        "test_too_many_locals",
+        &format!("{}print(v299);\n", declarations),
+        TopLevel::Private,
+    );
+}
+
+#[test]
+fn test_repeated_constants() {
+    run_test(
+        "test_repeated_constants",
+        r#"
+var a = "a";
+print(a + "a");
+print(a + "a");
+print(1 + 1);
+print(1 + 1);
+"#,
+    );
+}
+
+#[test]
+fn test_too_many_constants() {
+    let prints: String = (0..300).map(|i| format!("print({});\n", i)).collect();
+    run_test("test_too_many_constants", &prints);
+}
@@ -1,14 +1,14 @@
-#![feature(slice_ptr_get, slice_ptr_len)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
 use std::io;
 
-use crate::{driver::Driver, side_effect::PrintAllHandler};
+use crate::{compiler::TopLevel, driver::Driver, side_effect::PrintAllHandler};
 
 mod allocator;
 mod ast;
 mod compiler;
 mod constant;
+mod diagnostic;
 mod driver;
 mod insta;
 mod opcode;
@@ -34,6 +34,13 @@ print(cls());
     .to_string();
     println!("source = {}", source);
 
+    // The top-level variables are global unless `--private` is given.
+    let top_level = if std::env::args().any(|arg| arg == "--private") {
+        TopLevel::Private
+    } else {
+        TopLevel::Global
+    };
+
     let mut stdout = io::stdout().lock();
     let mut stderr = io::stderr().lock();
     let mut handler = PrintAllHandler {
@@ -44,6 +51,7 @@ print(cls());
         file_name: "inline source".into(),
         source,
         run: true,
+        top_level,
         handler: &mut handler,
     };
     driver.run();
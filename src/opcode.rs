@@ -19,11 +19,29 @@ pub(crate) enum OpCode {
     CloseUpvalue,
     Call,
     Return,
+    Reset,
+    Shift,
+    // Jumps
+    Jump,
+    JumpIfFalse,
+    Loop,
+    // Unary operators
+    Not,
+    Negate,
     // Binary operators
     Add,
     Sub,
     Mul,
     Div,
+    /// Concatenate the text of the given number of values.
+    Concat,
+    // Comparison operators
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
     // Global
     GetGlobal,
     SetGlobal,
@@ -34,6 +52,24 @@ pub(crate) enum OpCode {
     GetUpvalue,
     SetUpvalue,
     Closure,
+    // Class
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    /// Copy the methods of the superclass into the subclass.
+    Inherit,
+    /// Call the method of the receiver without creating the bound method.
+    Invoke,
+    GetSuper,
+    SuperInvoke,
+    // Collections
+    /// Create a list of the given number of values.
+    List,
+    /// Create a map of the given number of pairs of keys and values.
+    Map,
+    GetIndex,
+    SetIndex,
 }
 
 pub(crate) struct Chunk {
@@ -51,6 +87,11 @@ impl Chunk {
         &self.constants
     }
 
+    /// The source line of the instruction at the offset.
+    pub(crate) fn line(&self, offset: usize) -> usize {
+        self.lines[offset]
+    }
+
     fn print_simple(&self, writer: &mut dyn io::Write, name: &str) -> io::Result<usize> {
         writeln!(writer, " {:-16} |", name)?;
         Ok(1)
@@ -79,6 +120,26 @@ impl Chunk {
         Ok(2)
     }
 
+    fn print_jump(
+        &self,
+        writer: &mut dyn io::Write,
+        offset: usize,
+        name: &str,
+        forward: bool,
+    ) -> io::Result<usize> {
+        let jump = usize::from(u16::from_be_bytes([
+            self.code[offset + 1],
+            self.code[offset + 2],
+        ]));
+        let target = if forward {
+            offset + 3 + jump
+        } else {
+            offset + 3 - jump
+        };
+        writeln!(writer, " {:-16} | {} -> {}", name, offset, target)?;
+        Ok(3)
+    }
+
     fn print_closure(&self, writer: &mut dyn io::Write, offset: usize) -> io::Result<usize> {
         // OP_CLOSURE is a variable-length opcode where
         // | OP_CLOSURE | # of upvalues | (#1) true if values comes from local of the parent | (#1) the index in the local/upvalue | ... |
@@ -100,6 +161,25 @@ impl Chunk {
         Ok(2 + 2 * upvalues)
     }
 
+    fn print_invoke(
+        &self,
+        writer: &mut dyn io::Write,
+        offset: usize,
+        name: &str,
+    ) -> io::Result<usize> {
+        let index = self.code[offset + 1];
+        let constant = &self.constants[usize::from(index)];
+        let arguments_len = self.code[offset + 2];
+        writeln!(
+            writer,
+            " {:-16} | {} ({} arguments)",
+            name,
+            constant.display(),
+            arguments_len
+        )?;
+        Ok(3)
+    }
+
     pub(crate) fn write(&self, name: &str, writer: &mut dyn io::Write) -> io::Result<()> {
         writeln!(writer, "==== {} ====", name)?;
         writeln!(writer, " offset | line | {:-16} | constants", "opcode")?;
@@ -117,11 +197,27 @@ impl Chunk {
                 Some(OpCode::Print) => self.print_simple(writer, "OP_PRINT")?,
                 Some(OpCode::Call) => self.print_immediate(writer, offset, "OP_CALL")?,
                 Some(OpCode::Return) => self.print_simple(writer, "OP_RETURN")?,
+                Some(OpCode::Reset) => self.print_simple(writer, "OP_RESET")?,
+                Some(OpCode::Shift) => self.print_simple(writer, "OP_SHIFT")?,
+                Some(OpCode::Jump) => self.print_jump(writer, offset, "OP_JUMP", true)?,
+                Some(OpCode::JumpIfFalse) => {
+                    self.print_jump(writer, offset, "OP_JUMP_IF_FALSE", true)?
+                }
+                Some(OpCode::Loop) => self.print_jump(writer, offset, "OP_LOOP", false)?,
                 Some(OpCode::Constant) => self.print_constant(writer, offset, "OP_CONSTANT")?,
+                Some(OpCode::Not) => self.print_simple(writer, "OP_NOT")?,
+                Some(OpCode::Negate) => self.print_simple(writer, "OP_NEGATE")?,
                 Some(OpCode::Add) => self.print_simple(writer, "OP_ADD")?,
                 Some(OpCode::Sub) => self.print_simple(writer, "OP_SUB")?,
                 Some(OpCode::Mul) => self.print_simple(writer, "OP_MUL")?,
                 Some(OpCode::Div) => self.print_simple(writer, "OP_DIV")?,
+                Some(OpCode::Concat) => self.print_immediate(writer, offset, "OP_CONCAT")?,
+                Some(OpCode::Equal) => self.print_simple(writer, "OP_EQUAL")?,
+                Some(OpCode::NotEqual) => self.print_simple(writer, "OP_NOT_EQUAL")?,
+                Some(OpCode::Less) => self.print_simple(writer, "OP_LESS")?,
+                Some(OpCode::LessEqual) => self.print_simple(writer, "OP_LESS_EQUAL")?,
+                Some(OpCode::Greater) => self.print_simple(writer, "OP_GREATER")?,
+                Some(OpCode::GreaterEqual) => self.print_simple(writer, "OP_GREATER_EQUAL")?,
                 Some(OpCode::GetGlobal) => self.print_constant(writer, offset, "OP_GET_GLOBAL")?,
                 Some(OpCode::SetGlobal) => self.print_constant(writer, offset, "OP_SET_GLOBAL")?,
                 Some(OpCode::GetLocal) => self.print_immediate(writer, offset, "OP_GET_LOCAL")?,
@@ -133,6 +229,24 @@ impl Chunk {
                     self.print_immediate(writer, offset, "OP_SET_UPVALUE")?
                 }
                 Some(OpCode::Closure) => self.print_closure(writer, offset)?,
+                Some(OpCode::Class) => self.print_constant(writer, offset, "OP_CLASS")?,
+                Some(OpCode::GetProperty) => {
+                    self.print_constant(writer, offset, "OP_GET_PROPERTY")?
+                }
+                Some(OpCode::SetProperty) => {
+                    self.print_constant(writer, offset, "OP_SET_PROPERTY")?
+                }
+                Some(OpCode::Method) => self.print_constant(writer, offset, "OP_METHOD")?,
+                Some(OpCode::Inherit) => self.print_simple(writer, "OP_INHERIT")?,
+                Some(OpCode::Invoke) => self.print_invoke(writer, offset, "OP_INVOKE")?,
+                Some(OpCode::GetSuper) => self.print_constant(writer, offset, "OP_GET_SUPER")?,
+                Some(OpCode::SuperInvoke) => {
+                    self.print_invoke(writer, offset, "OP_SUPER_INVOKE")?
+                }
+                Some(OpCode::List) => self.print_immediate(writer, offset, "OP_LIST")?,
+                Some(OpCode::Map) => self.print_immediate(writer, offset, "OP_MAP")?,
+                Some(OpCode::GetIndex) => self.print_simple(writer, "OP_GET_INDEX")?,
+                Some(OpCode::SetIndex) => self.print_simple(writer, "OP_SET_INDEX")?,
             }
         }
         Ok(())
@@ -156,10 +270,55 @@ impl ChunkBuilder {
         self.lines.push(line);
     }
 
-    pub(crate) fn push_constant(&mut self, constant: Constant) -> u8 {
-        let index = self.constants.len();
-        self.constants.push(constant);
-        u8::try_from(index).unwrap()
+    /// Emit a jump instruction with a placeholder offset, and returns the position of the offset to patch.
+    pub(crate) fn push_jump(&mut self, opcode: OpCode, line: usize) -> usize {
+        self.push_op(opcode, line);
+        self.push_u8(0xff, line);
+        self.push_u8(0xff, line);
+        self.code.len() - 2
+    }
+
+    /// Patch the offset of the jump instruction to jump to the next instruction to be emitted.
+    ///
+    /// The offset is relative to the instruction following the jump.
+    pub(crate) fn patch_jump(&mut self, position: usize) -> Result<(), String> {
+        let jump = u16::try_from(self.code.len() - position - 2)
+            .map_err(|_| "too much code to jump over".to_string())?;
+        self.code[position..position + 2].copy_from_slice(&jump.to_be_bytes());
+        Ok(())
+    }
+
+    /// Emit a jump instruction going back to the start position.
+    ///
+    /// The instruction is emitted even if the jump is too long, so that the rest of the code can be compiled.
+    pub(crate) fn push_loop(&mut self, start: usize, line: usize) -> Result<(), String> {
+        self.push_op(OpCode::Loop, line);
+        let jump = u16::try_from(self.code.len() + 2 - start);
+        let [high, low] = jump.unwrap_or(u16::MAX).to_be_bytes();
+        self.push_u8(high, line);
+        self.push_u8(low, line);
+        jump.map(|_| ())
+            .map_err(|_| "too much code to jump over".to_string())
+    }
+
+    /// The position of the next instruction to be emitted.
+    pub(crate) fn position(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Push the constant to the constants of the chunk, and returns its index.
+    ///
+    /// Fails when the index doesn't fit in the 8-bit operand.
+    pub(crate) fn push_constant(&mut self, constant: Constant) -> Result<u8, String> {
+        // If the constant is already pushed, return it.
+        let index = match self.constants.iter().position(|c| c.is_same(&constant)) {
+            Some(index) => index,
+            None => {
+                self.constants.push(constant);
+                self.constants.len() - 1
+            }
+        };
+        u8::try_from(index).map_err(|_| "too many constants in a function".to_string())
     }
 
     pub(crate) fn build(&mut self) -> Chunk {
@@ -171,3 +330,53 @@ impl ChunkBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod test_chunk_builder {
+    use super::{ChunkBuilder, OpCode};
+    use crate::constant::Constant;
+
+    /// Push `len` bytes of OP_NIL.
+    fn push_nils(builder: &mut ChunkBuilder, len: usize) {
+        for _ in 0..len {
+            builder.push_op(OpCode::Nil, 1);
+        }
+    }
+
+    #[test]
+    fn test_patch_jump_too_long() {
+        let mut builder = ChunkBuilder::default();
+        let longest = builder.push_jump(OpCode::Jump, 1);
+        push_nils(&mut builder, usize::from(u16::MAX));
+        assert_eq!(builder.patch_jump(longest), Ok(()));
+
+        let mut builder = ChunkBuilder::default();
+        let too_long = builder.push_jump(OpCode::Jump, 1);
+        push_nils(&mut builder, usize::from(u16::MAX) + 1);
+        assert!(builder.patch_jump(too_long).is_err());
+    }
+
+    #[test]
+    fn test_push_loop_too_long() {
+        // The jump goes back over the body and the 3 bytes of OP_LOOP.
+        let mut builder = ChunkBuilder::default();
+        push_nils(&mut builder, usize::from(u16::MAX) - 3);
+        assert_eq!(builder.push_loop(0, 1), Ok(()));
+
+        let mut builder = ChunkBuilder::default();
+        push_nils(&mut builder, usize::from(u16::MAX) - 2);
+        assert!(builder.push_loop(0, 1).is_err());
+    }
+
+    #[test]
+    fn test_push_constant_shares_repeated_constants() {
+        let mut builder = ChunkBuilder::default();
+
+        assert_eq!(builder.push_constant(Constant::String("a".into())), Ok(0));
+        assert_eq!(builder.push_constant(Constant::Number(1.0)), Ok(1));
+        assert_eq!(builder.push_constant(Constant::String("a".into())), Ok(0));
+        assert_eq!(builder.push_constant(Constant::Number(1.0)), Ok(1));
+        assert_eq!(builder.push_constant(Constant::Number(-1.0)), Ok(2));
+        assert_eq!(builder.build().constants().len(), 3);
+    }
+}
@@ -1,15 +1,23 @@
-use std::{collections::HashSet, ops::Range};
+use std::{collections::HashSet, num::IntErrorKind, ops::Range};
 
 use chumsky::{
-    prelude::{end, filter, just, recursive, Simple},
-    text::{ident, int, keyword, TextParser},
-    Parser,
+    combinator::{IgnoreThen, ThenIgnore},
+    prelude::{any, end, filter, just, one_of, recursive, take_until, Recursive, Simple},
+    text::{ident, keyword},
+    BoxedParser, Parser,
 };
 #[cfg(not(miri))]
 use once_cell::sync::Lazy;
 use typed_arena::Arena;
 
-use crate::ast::{Ast, AstBody};
+use crate::ast::{Ast, AstBody, Span};
+
+/// The operations following an expression in a call chain.
+enum Postfix<'arena> {
+    Call(Vec<Ast<'arena>>),
+    Property(String),
+    Index(Ast<'arena>),
+}
 
 #[derive(Debug)]
 pub(crate) struct LineMapper {
@@ -34,27 +42,9 @@ impl LineMapper {
             Err(l) => l,
         }
     }
-}
-
-#[cfg(test)]
-mod test_line_mapper {
-    use super::LineMapper;
 
-    #[test]
-    fn test_line_mapper() {
-        let source = r#"abc
-defg"#;
-        let mapper = LineMapper::new(source);
-
-        assert_eq!(mapper.find(0), 1);
-        assert_eq!(mapper.find(1), 1);
-        assert_eq!(mapper.find(2), 1);
-        assert_eq!(mapper.find(3), 1);
-        assert_eq!(mapper.find(4), 2);
-        assert_eq!(mapper.find(5), 2);
-        assert_eq!(mapper.find(6), 2);
-        assert_eq!(mapper.find(7), 2);
-        assert_eq!(mapper.find(8), 2);
+    pub(crate) fn find_column(&self, idx: usize) -> usize {
+        idx - self.lines[self.find(idx) - 1] + 1
     }
 }
 
@@ -64,6 +54,23 @@ fn generate_keyword_set() -> HashSet<&'static str> {
     keywords.insert("fun");
     keywords.insert("print");
     keywords.insert("return");
+    keywords.insert("reset");
+    keywords.insert("shift");
+    keywords.insert("if");
+    keywords.insert("else");
+    keywords.insert("while");
+    keywords.insert("for");
+    keywords.insert("break");
+    keywords.insert("continue");
+    keywords.insert("and");
+    keywords.insert("or");
+    keywords.insert("nil");
+    keywords.insert("true");
+    keywords.insert("false");
+    keywords.insert("export");
+    keywords.insert("class");
+    keywords.insert("this");
+    keywords.insert("super");
     keywords
 }
 
@@ -88,56 +95,448 @@ fn allowed_ident() -> impl Parser<char, String, Error = Simple<char>> + Clone {
     })
 }
 
+/// Remove the digit separators `_`, which must be placed between digits.
+fn remove_separators(digits: &str, radix: u32) -> Result<String, String> {
+    let chars: Vec<char> = digits.chars().collect();
+    let is_digit = |index: Option<usize>| {
+        index
+            .and_then(|index| chars.get(index))
+            .is_some_and(|c| c.is_digit(radix))
+    };
+    for (index, c) in chars.iter().enumerate() {
+        if *c == '_' && !(is_digit(index.checked_sub(1)) && is_digit(Some(index + 1))) {
+            return Err("`_` must be placed between digits".into());
+        }
+    }
+    Ok(digits.replace('_', ""))
+}
+
+fn parse_radix(digits: &str, prefix: &str, radix: u32) -> Result<f64, String> {
+    let digits = remove_separators(digits, radix)?;
+    match u64::from_str_radix(&digits, radix) {
+        Ok(n) => Ok(n as f64),
+        Err(error) => Err(match error.kind() {
+            IntErrorKind::Empty => format!("missing digits after {}", prefix),
+            IntErrorKind::PosOverflow => "number literal is too large".into(),
+            _ => format!("invalid digit in base {} number literal", radix),
+        }),
+    }
+}
+
+fn parse_decimal(literal: &str) -> Result<f64, String> {
+    let literal = remove_separators(literal, 10)?;
+    match literal.parse::<f64>() {
+        Ok(n) if n.is_finite() => Ok(n),
+        _ => Err("number literal is out of range".into()),
+    }
+}
+
+/// Report the error of the invalid literal without failing the parser,
+/// so that the error is not hidden by the other alternatives.
+fn emit_literal_error(
+    result: Result<f64, String>,
+    span: Range<usize>,
+    emit: &mut dyn FnMut(Simple<char>),
+) -> f64 {
+    result.unwrap_or_else(|message| {
+        emit(Simple::custom(span, message));
+        f64::NAN
+    })
+}
+
+/// Decimal numbers with an optional fraction and exponent, or hexadecimal and binary integers.
+///
+/// Digits can be separated by `_`.
+fn number_literal() -> impl Parser<char, f64, Error = Simple<char>> + Clone {
+    let digits = || {
+        filter(|c: &char| c.is_ascii_digit())
+            .chain(filter(|c: &char| c.is_ascii_digit() || *c == '_').repeated())
+    };
+    let fraction = just('.').chain(digits());
+    let exponent = one_of("eE")
+        .chain(one_of("+-").or_not())
+        .chain::<char, _, _>(digits());
+
+    let decimal = digits()
+        .chain::<char, _, _>(fraction.or_not().flatten())
+        .chain::<char, _, _>(exponent.or_not().flatten())
+        .collect::<String>()
+        .map(|literal| parse_decimal(&literal));
+
+    // Integers with the `0x` or `0b` prefix. The prefix is committed to before parsing the digits
+    // so that an invalid literal such as `0b102` is not parsed as the decimal `0` instead.
+    let radix_digits = filter(|c: &char| c.is_ascii_alphanumeric() || *c == '_')
+        .repeated()
+        .collect::<String>();
+    let radix_prefix = just("0x").to(16).or(just("0b").to(2));
+
+    radix_prefix
+        .or_not()
+        .then_with(move |radix| match radix {
+            Some(radix) => radix_digits
+                .map(move |digits| {
+                    let prefix = if radix == 16 { "0x" } else { "0b" };
+                    parse_radix(&digits, prefix, radix)
+                })
+                .boxed(),
+            None => decimal.clone().boxed(),
+        })
+        .validate(emit_literal_error)
+}
+
+fn parse_unicode_escape(digits: &str) -> Result<char, String> {
+    if digits.is_empty() || digits.len() > 6 {
+        return Err("unicode escape must have 1 to 6 hex digits".into());
+    }
+    u32::from_str_radix(digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| format!("invalid unicode escape \\u{{{}}}", digits))
+}
+
+/// An escape sequence in a string literal. Invalid escapes are reported without failing the
+/// parser and are replaced by U+FFFD.
+fn escape() -> impl Parser<char, char, Error = Simple<char>> + Clone {
+    let unicode = just('u').ignore_then(
+        filter(|c: &char| *c != '}' && *c != '"')
+            .repeated()
+            .collect::<String>()
+            .delimited_by(just('{'), just('}'))
+            .map(|digits| parse_unicode_escape(&digits)),
+    );
+    let simple = any().map(|c| match c {
+        'n' => Ok('\n'),
+        't' => Ok('\t'),
+        'r' => Ok('\r'),
+        '\\' => Ok('\\'),
+        '"' => Ok('"'),
+        '$' => Ok('$'),
+        'u' => Err("unicode escape must be written as \\u{...}".into()),
+        c => Err(format!("unknown escape sequence \\{}", c)),
+    });
+
+    just('\\')
+        .ignore_then(unicode.or(simple))
+        .validate(|result, span, emit| {
+            result.unwrap_or_else(|message| {
+                emit(Simple::custom(span, message));
+                char::REPLACEMENT_CHARACTER
+            })
+        })
+}
+
+/// A string literal, either quoted with `"` and containing escape sequences and `${...}`
+/// interpolations, or quoted with `"""` and taken verbatim, which is handy for multi-line strings.
+fn string_literal<'arena>(
+    arena: &'arena Arena<AstBody<'arena>>,
+    expr: impl Parser<char, Ast<'arena>, Error = Simple<char>> + Clone,
+) -> impl Parser<char, Ast<'arena>, Error = Simple<char>> + Clone {
+    let string = move |literal, span: Range<usize>| Ast {
+        body: arena.alloc(AstBody::String(literal)),
+        span: span.into(),
+    };
+
+    let raw = just("\"\"\"")
+        .ignore_then(take_until(just("\"\"\"")))
+        .map(|(literal, _)| literal.into_iter().collect())
+        .map_with_span(string);
+
+    // `$` starts an interpolation only when followed by `{`.
+    let dollar = just('$').then_ignore(filter(|c| *c != '{').rewind());
+    let text = escape()
+        .or(filter(|c| !matches!(c, '"' | '\\' | '$')))
+        .or(dollar)
+        .repeated()
+        .at_least(1)
+        .collect::<String>()
+        .map_with_span(string);
+    let interpolation = expr.delimited_by(just("${"), just('}'));
+    let escaped = text
+        .or(interpolation)
+        .repeated()
+        .delimited_by(just('"'), just('"'))
+        .map_with_span(
+            move |parts: Vec<Ast<'arena>>, span: Range<usize>| match parts.as_slice() {
+                [] => string(String::new(), span),
+                [part] if matches!(part.body, AstBody::String(_)) => Ast {
+                    body: part.body,
+                    span: span.into(),
+                },
+                _ => Ast {
+                    body: arena.alloc(AstBody::Interpolation(parts)),
+                    span: span.into(),
+                },
+            },
+        );
+
+    raw.or(escaped)
+}
+
+type Trivia = BoxedParser<'static, char, (), Simple<char>>;
+
+/// The opener of a comment, `/` followed by `second`.
+///
+/// The first `/` is matched by `filter`, which expects nothing when it fails,
+/// so that the comments skipped around every token are not reported as expected.
+fn comment_opener(second: char) -> impl Parser<char, (), Error = Simple<char>> + Clone {
+    filter(|c| *c == '/').ignore_then(just(second)).ignored()
+}
+
+/// Whitespace, `//` line comments and nestable `/* */` block comments.
+fn trivia() -> Trivia {
+    let line_comment = comment_opener('/')
+        .then(filter(|c| *c != '\n').repeated())
+        .ignored();
+    let block_comment = recursive(|block_comment| {
+        // `*` and `/` are part of the comment unless they close or open a block comment.
+        let star = just('*').then(filter(|c| *c != '/').rewind()).ignored();
+        let slash = just('/').then(filter(|c| *c != '*').rewind()).ignored();
+        let other = filter(|c| *c != '*' && *c != '/').ignored();
+        comment_opener('*')
+            .ignore_then(block_comment.or(star).or(slash).or(other).repeated())
+            .ignore_then(just("*/").or_not())
+            .validate(|close, span: Range<usize>, emit| {
+                if close.is_none() {
+                    emit(Simple::custom(
+                        span.start..span.start + 2,
+                        "unclosed block comment",
+                    ));
+                }
+            })
+    });
+
+    filter(|c: &char| c.is_whitespace())
+        .ignored()
+        .or(line_comment)
+        .or(block_comment)
+        .repeated()
+        .ignored()
+        .boxed()
+}
+
+trait PaddedWithComments<O>: Parser<char, O, Error = Simple<char>> + Sized {
+    /// Like `TextParser::padded`, but skips comments as well as whitespace.
+    fn padded_with_comments(self) -> ThenIgnore<IgnoreThen<Trivia, Self, (), O>, Trivia, O, ()> {
+        self.padded_by(trivia())
+    }
+}
+
+impl<O, P: Parser<char, O, Error = Simple<char>>> PaddedWithComments<O> for P {}
+
 #[allow(clippy::let_and_return)]
 pub(crate) fn parser<'arena>(
     arena: &'arena Arena<AstBody<'arena>>,
 ) -> impl Parser<char, Ast<'arena>, Error = Simple<char>> {
+    let mut stmt = Recursive::declare();
+
+    let parameters = allowed_ident()
+        .separated_by(just(',').padded_with_comments())
+        .allow_trailing()
+        .delimited_by(just('('), just(')'))
+        .padded_with_comments();
+    let function_body = stmt
+        .clone()
+        .repeated()
+        .padded_with_comments()
+        .delimited_by(just('{'), just('}'));
+
     let expr = recursive(|expr| {
-        let simple_string_literal = just('"')
-            .ignore_then(filter(|c| *c != '"').repeated())
-            .then_ignore(just('"'))
-            .collect::<String>()
-            .map_with_span(|literal, span: Range<usize>| Ast {
-                body: arena.alloc(AstBody::String(literal)),
+        let string = string_literal(arena, expr.clone()).padded_with_comments();
+
+        let number = number_literal()
+            .map_with_span(|number, span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::Number(number)),
                 span: span.into(),
             })
-            .padded();
+            .padded_with_comments();
 
-        let number = int(10)
-            .map_with_span(|literal: String, span: Range<usize>| Ast {
-                body: arena.alloc(AstBody::Number(literal.parse().unwrap())),
+        // nil is represented as None here.
+        let literal = keyword("nil")
+            .to(None)
+            .or(keyword("true").to(Some(true)))
+            .or(keyword("false").to(Some(false)))
+            .map_with_span(|literal, span: Range<usize>| Ast {
+                body: arena.alloc(match literal {
+                    None => AstBody::Nil,
+                    Some(boolean) => AstBody::Boolean(boolean),
+                }),
                 span: span.into(),
             })
-            .padded();
+            .padded_with_comments();
 
         let var = allowed_ident()
             .map_with_span(|ident, span: Range<usize>| Ast {
                 body: arena.alloc(AstBody::Var(ident)),
                 span: span.into(),
             })
-            .padded();
+            .padded_with_comments();
 
-        let primitive = simple_string_literal.or(number).or(var);
+        let this = keyword("this")
+            .map_with_span(|_, span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::This),
+                span: span.into(),
+            })
+            .padded_with_comments();
+
+        let super_method = keyword("super")
+            .ignore_then(just('.').padded_with_comments())
+            .ignore_then(allowed_ident())
+            .map_with_span(|name, span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::Super(name)),
+                span: span.into(),
+            })
+            .padded_with_comments();
+
+        let reset = keyword("reset")
+            .ignore_then(
+                expr.clone()
+                    .delimited_by(just('('), just(')'))
+                    .padded_with_comments(),
+            )
+            .map_with_span(|expr, span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::Reset(expr)),
+                span: span.into(),
+            })
+            .padded_with_comments();
+
+        let shift = keyword("shift")
+            .ignore_then(
+                expr.clone()
+                    .delimited_by(just('('), just(')'))
+                    .padded_with_comments(),
+            )
+            .map_with_span(|expr, span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::Shift(expr)),
+                span: span.into(),
+            })
+            .padded_with_comments();
+
+        let grouping = expr
+            .clone()
+            .delimited_by(just('('), just(')'))
+            .padded_with_comments();
+
+        let list = expr
+            .clone()
+            .separated_by(just(',').padded_with_comments())
+            .allow_trailing()
+            .padded_with_comments()
+            .delimited_by(just('['), just(']'))
+            .map_with_span(|elements, span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::List(elements)),
+                span: span.into(),
+            })
+            .padded_with_comments();
+
+        // A statement starting with `{` is a block, so `{}` is an empty block there.
+        let map = expr
+            .clone()
+            .then_ignore(just(':').padded_with_comments())
+            .then(expr.clone())
+            .separated_by(just(',').padded_with_comments())
+            .allow_trailing()
+            .padded_with_comments()
+            .delimited_by(just('{'), just('}'))
+            .map_with_span(|entries, span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::Map(entries)),
+                span: span.into(),
+            })
+            .padded_with_comments();
+
+        // `fun (x) { ... }`, or `(x) => expr` returning the expression.
+        let lambda = keyword("fun")
+            .ignore_then(parameters.clone())
+            .then(function_body.clone())
+            .or(parameters
+                .clone()
+                .then_ignore(just("=>").padded_with_comments())
+                .then(expr.clone().map(|body: Ast<'arena>| {
+                    vec![Ast {
+                        body: arena.alloc(AstBody::Return(Some(body))),
+                        span: body.span,
+                    }]
+                })))
+            .map_with_span(|(parameters, body), span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::Lambda { parameters, body }),
+                span: span.into(),
+            })
+            .padded_with_comments();
+
+        let primitive = string
+            .or(number)
+            .or(literal)
+            .or(reset)
+            .or(shift)
+            .or(lambda)
+            .or(this)
+            .or(super_method)
+            .or(var)
+            .or(list)
+            .or(map)
+            .or(grouping);
+
+        let arguments = expr
+            .clone()
+            .separated_by(just(',').padded_with_comments())
+            .allow_trailing()
+            .delimited_by(just('('), just(')'))
+            .map(Postfix::Call);
+        let property = just('.')
+            .padded_with_comments()
+            .ignore_then(allowed_ident())
+            .map(Postfix::Property);
+        let index = expr
+            .clone()
+            .delimited_by(just('['), just(']'))
+            .map(Postfix::Index);
 
         let call = primitive
             .then(
-                expr.separated_by(just(',').padded())
-                    .allow_trailing()
-                    .delimited_by(just('('), just(')'))
-                    .padded()
+                arguments
+                    .or(property)
+                    .or(index)
+                    .map_with_span(|postfix, span: Range<usize>| (postfix, Span::from(span)))
+                    .padded_with_comments()
                     .repeated(),
             )
-            .foldl(|callee, arguments| Ast {
-                span: arguments
-                    .iter()
-                    .fold(callee.span, |span, ast: &Ast<'_>| span.merge(ast.span)),
-                body: arena.alloc(AstBody::Call { callee, arguments }),
+            .foldl(|callee, (postfix, span)| Ast {
+                span: callee.span.merge(span),
+                body: arena.alloc(match postfix {
+                    Postfix::Call(arguments) => AstBody::Call { callee, arguments },
+                    Postfix::Property(name) => AstBody::GetProperty {
+                        object: callee,
+                        name,
+                    },
+                    Postfix::Index(index) => AstBody::GetIndex {
+                        object: callee,
+                        index,
+                    },
+                }),
             })
-            .padded();
+            .padded_with_comments()
+            .boxed();
 
-        let factor = call
+        let unary = just('!')
+            .or(just('-'))
+            .map_with_span(|op, span: Range<usize>| (op, span))
+            .padded_with_comments()
+            .repeated()
+            .then(call)
+            .foldr(|(op, span), expr| {
+                let body = match op {
+                    '!' => AstBody::Not(expr),
+                    '-' => AstBody::Negate(expr),
+                    _ => unreachable!(),
+                };
+                Ast {
+                    body: arena.alloc(body),
+                    span: expr.span.merge(span.into()),
+                }
+            });
+
+        let factor = unary
             .clone()
-            .then(just('*').or(just('/')).then(call).repeated())
+            .then(just('*').or(just('/')).then(unary).repeated())
             .foldl(|lhs, (op, rhs)| match op {
                 '*' => Ast {
                     body: arena.alloc(AstBody::Mul(lhs, rhs)),
@@ -163,41 +562,133 @@ pub(crate) fn parser<'arena>(
                     span: lhs.merge_span(rhs),
                 },
                 _ => unreachable!(),
+            })
+            .boxed();
+
+        let comparison = term
+            .clone()
+            .then(
+                just("<=")
+                    .or(just("<"))
+                    .or(just(">="))
+                    .or(just(">"))
+                    .then(term)
+                    .repeated(),
+            )
+            .foldl(|lhs, (op, rhs)| {
+                let body = match op {
+                    "<" => AstBody::Less(lhs, rhs),
+                    "<=" => AstBody::LessEqual(lhs, rhs),
+                    ">" => AstBody::Greater(lhs, rhs),
+                    ">=" => AstBody::GreaterEqual(lhs, rhs),
+                    _ => unreachable!(),
+                };
+                Ast {
+                    body: arena.alloc(body),
+                    span: lhs.merge_span(rhs),
+                }
+            });
+
+        let equality = comparison
+            .clone()
+            .then(just("==").or(just("!=")).then(comparison).repeated())
+            .foldl(|lhs, (op, rhs)| {
+                let body = match op {
+                    "==" => AstBody::Equal(lhs, rhs),
+                    "!=" => AstBody::NotEqual(lhs, rhs),
+                    _ => unreachable!(),
+                };
+                Ast {
+                    body: arena.alloc(body),
+                    span: lhs.merge_span(rhs),
+                }
+            })
+            .boxed();
+
+        let and = equality
+            .clone()
+            .then(
+                keyword("and")
+                    .padded_with_comments()
+                    .ignore_then(equality)
+                    .repeated(),
+            )
+            .foldl(|lhs, rhs| Ast {
+                body: arena.alloc(AstBody::And(lhs, rhs)),
+                span: lhs.merge_span(rhs),
+            });
+
+        let or = and
+            .clone()
+            .then(
+                keyword("or")
+                    .padded_with_comments()
+                    .ignore_then(and)
+                    .repeated(),
+            )
+            .foldl(|lhs, rhs| Ast {
+                body: arena.alloc(AstBody::Or(lhs, rhs)),
+                span: lhs.merge_span(rhs),
             });
 
-        term
+        or
     });
 
-    let stmt = recursive(move |stmt| {
+    stmt.define({
         let print_stmt = keyword("print")
-            .padded()
-            .ignore_then(expr.clone().delimited_by(just('('), just(')')).padded())
+            .padded_with_comments()
+            .ignore_then(
+                expr.clone()
+                    .delimited_by(just('('), just(')'))
+                    .padded_with_comments(),
+            )
             .then_ignore(just(';'))
             .map_with_span(|expr, span: Range<usize>| Ast {
                 body: arena.alloc(AstBody::Print(expr)),
                 span: span.into(),
             })
-            .padded();
+            .padded_with_comments();
 
-        let assign_stmt = allowed_ident()
-            .padded()
-            .then_ignore(just('=').padded())
-            .then(expr.clone())
-            .then_ignore(just(';'))
-            .map_with_span(|(ident, expr), span: Range<usize>| Ast {
-                body: arena.alloc(AstBody::Assign(ident, expr)),
-                span: span.into(),
-            })
-            .padded();
+        // An assignment to a variable or a property, or an expression whose value is discarded.
+        let assign_or_expr = expr
+            .clone()
+            .then(
+                just('=')
+                    .padded_with_comments()
+                    .ignore_then(expr.clone())
+                    .or_not(),
+            )
+            .try_map(|(target, value), span: Range<usize>| {
+                let body = match (target.body, value) {
+                    (_, None) => AstBody::ExprStmt { expr: target },
+                    (AstBody::Var(ident), Some(value)) => AstBody::Assign(ident.clone(), value),
+                    (AstBody::GetProperty { object, name }, Some(value)) => AstBody::SetProperty {
+                        object: *object,
+                        name: name.clone(),
+                        value,
+                    },
+                    (AstBody::GetIndex { object, index }, Some(value)) => AstBody::SetIndex {
+                        object: *object,
+                        index: *index,
+                        value,
+                    },
+                    (_, Some(_)) => {
+                        return Err(Simple::custom(
+                            target.span.start..target.span.end,
+                            "invalid assignment target",
+                        ))
+                    }
+                };
+                Ok(Ast {
+                    body: arena.alloc(body),
+                    span: span.into(),
+                })
+            });
 
-        let expr_stmt = expr
+        let expr_stmt = assign_or_expr
             .clone()
             .then_ignore(just(';'))
-            .map_with_span(|expr, span: Range<usize>| Ast {
-                body: arena.alloc(AstBody::ExprStmt { expr }),
-                span: span.into(),
-            })
-            .padded();
+            .padded_with_comments();
 
         let return_stmt = keyword("return")
             .ignore_then(expr.clone().or_not())
@@ -206,45 +697,210 @@ pub(crate) fn parser<'arena>(
                 body: arena.alloc(AstBody::Return(expr)),
                 span: span.into(),
             })
-            .padded();
+            .padded_with_comments();
+
+        let export = keyword("export")
+            .padded_with_comments()
+            .or_not()
+            .map(|export| export.is_some());
 
-        let var_decl = keyword("var")
-            .padded()
-            .ignore_then(allowed_ident().padded())
-            .then(just('=').ignore_then(expr).or_not())
+        let var_decl = export
+            .clone()
+            .then_ignore(keyword("var").padded_with_comments())
+            .then(allowed_ident().padded_with_comments())
+            .then(just('=').ignore_then(expr.clone()).or_not())
             .then_ignore(just(';'))
-            .map_with_span(|(ident, initializer), span: Range<usize>| Ast {
-                body: arena.alloc(AstBody::VarDecl { ident, initializer }),
+            .map_with_span(|((exported, ident), initializer), span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::VarDecl {
+                    exported,
+                    ident,
+                    initializer,
+                }),
                 span: span.into(),
             })
-            .padded();
+            .padded_with_comments();
 
-        let fun_decl = keyword("fun")
-            .ignore_then(allowed_ident().padded())
-            .then(
-                allowed_ident()
-                    .separated_by(just(',').padded())
-                    .allow_trailing()
-                    .delimited_by(just('('), just(')'))
-                    .padded(),
+        let fun_decl = export
+            .clone()
+            .then_ignore(keyword("fun"))
+            .then(allowed_ident().padded_with_comments())
+            .then(parameters.clone())
+            .then(function_body.clone())
+            .map_with_span(
+                |(((exported, ident), parameters), body), span: Range<usize>| Ast {
+                    body: arena.alloc(AstBody::FunDecl {
+                        exported,
+                        ident,
+                        parameters,
+                        body,
+                    }),
+                    span: span.into(),
+                },
             )
-            .then(stmt.clone().repeated().delimited_by(just('{'), just('}')))
+            .padded_with_comments();
+
+        // A method is declared like a function without `fun`.
+        let method = allowed_ident()
+            .padded_with_comments()
+            .then(parameters)
+            .then(function_body)
             .map_with_span(|((ident, parameters), body), span: Range<usize>| Ast {
                 body: arena.alloc(AstBody::FunDecl {
+                    exported: false,
                     ident,
                     parameters,
                     body,
                 }),
                 span: span.into(),
             })
-            .padded();
+            .padded_with_comments();
+
+        let class_decl = export
+            .clone()
+            .then_ignore(keyword("class"))
+            .then(allowed_ident().padded_with_comments())
+            .then(
+                just('<')
+                    .padded_with_comments()
+                    .ignore_then(
+                        allowed_ident().map_with_span(|ident, span: Range<usize>| Ast {
+                            body: arena.alloc(AstBody::Var(ident)),
+                            span: span.into(),
+                        }),
+                    )
+                    .padded_with_comments()
+                    .or_not(),
+            )
+            .then(
+                method
+                    .repeated()
+                    .padded_with_comments()
+                    .delimited_by(just('{'), just('}')),
+            )
+            .map_with_span(
+                |(((exported, ident), superclass), methods), span: Range<usize>| Ast {
+                    body: arena.alloc(AstBody::ClassDecl {
+                        exported,
+                        ident,
+                        superclass,
+                        methods,
+                    }),
+                    span: span.into(),
+                },
+            )
+            .padded_with_comments();
+
+        let block = stmt
+            .clone()
+            .repeated()
+            .padded_with_comments()
+            .delimited_by(just('{'), just('}'));
+
+        let block_stmt = block
+            .clone()
+            .map_with_span(|stmts, span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::Block(stmts)),
+                span: span.into(),
+            })
+            .padded_with_comments();
+
+        let if_stmt = recursive(|if_stmt| {
+            keyword("if")
+                .ignore_then(
+                    expr.clone()
+                        .delimited_by(just('('), just(')'))
+                        .padded_with_comments(),
+                )
+                .then(block.clone())
+                .then(
+                    keyword("else")
+                        .padded_with_comments()
+                        .ignore_then(block.clone().or(if_stmt.map(|if_stmt| vec![if_stmt])))
+                        .or_not(),
+                )
+                .map_with_span(
+                    |((condition, then_branch), else_branch), span: Range<usize>| Ast {
+                        body: arena.alloc(AstBody::If {
+                            condition,
+                            then_branch,
+                            else_branch,
+                        }),
+                        span: span.into(),
+                    },
+                )
+        })
+        .padded_with_comments();
+
+        let while_stmt = keyword("while")
+            .ignore_then(
+                expr.clone()
+                    .delimited_by(just('('), just(')'))
+                    .padded_with_comments(),
+            )
+            .then(block.clone())
+            .map_with_span(|(condition, body), span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::While { condition, body }),
+                span: span.into(),
+            })
+            .padded_with_comments();
+
+        let for_stmt = keyword("for")
+            .ignore_then(
+                var_decl
+                    .clone()
+                    .or(expr_stmt.clone())
+                    .map(Some)
+                    .or(just(';').padded_with_comments().to(None))
+                    .then(expr.clone().or_not())
+                    .then_ignore(just(';'))
+                    .then(assign_or_expr.padded_with_comments().or_not())
+                    .delimited_by(just('('), just(')'))
+                    .padded_with_comments(),
+            )
+            .then(block.clone())
+            .map_with_span(
+                |(((initializer, condition), increment), body), span: Range<usize>| Ast {
+                    body: arena.alloc(AstBody::For {
+                        initializer,
+                        condition,
+                        increment,
+                        body,
+                    }),
+                    span: span.into(),
+                },
+            )
+            .padded_with_comments();
+
+        let break_stmt = keyword("break")
+            .padded_with_comments()
+            .then_ignore(just(';'))
+            .map_with_span(|_, span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::Break),
+                span: span.into(),
+            })
+            .padded_with_comments();
+
+        let continue_stmt = keyword("continue")
+            .padded_with_comments()
+            .then_ignore(just(';'))
+            .map_with_span(|_, span: Range<usize>| Ast {
+                body: arena.alloc(AstBody::Continue),
+                span: span.into(),
+            })
+            .padded_with_comments();
 
         print_stmt
-            .or(assign_stmt)
+            .or(block_stmt)
+            .or(if_stmt)
+            .or(while_stmt)
+            .or(for_stmt)
+            .or(break_stmt)
+            .or(continue_stmt)
             .or(expr_stmt)
             .or(return_stmt)
             .or(var_decl)
             .or(fun_decl)
+            .or(class_decl)
     });
 
     let program = stmt
@@ -254,5 +910,40 @@ pub(crate) fn parser<'arena>(
             span: span.into(),
         });
 
-    program.then_ignore(end())
+    program.padded_with_comments().then_ignore(end())
+}
+
+#[cfg(test)]
+mod test_line_mapper {
+    use super::LineMapper;
+
+    #[test]
+    fn test_line_mapper() {
+        let source = r#"abc
+defg"#;
+        let mapper = LineMapper::new(source);
+
+        assert_eq!(mapper.find(0), 1);
+        assert_eq!(mapper.find(1), 1);
+        assert_eq!(mapper.find(2), 1);
+        assert_eq!(mapper.find(3), 1);
+        assert_eq!(mapper.find(4), 2);
+        assert_eq!(mapper.find(5), 2);
+        assert_eq!(mapper.find(6), 2);
+        assert_eq!(mapper.find(7), 2);
+        assert_eq!(mapper.find(8), 2);
+    }
+
+    #[test]
+    fn test_line_mapper_column() {
+        let source = r#"abc
+defg"#;
+        let mapper = LineMapper::new(source);
+
+        assert_eq!(mapper.find_column(0), 1);
+        assert_eq!(mapper.find_column(2), 3);
+        assert_eq!(mapper.find_column(3), 4);
+        assert_eq!(mapper.find_column(4), 1);
+        assert_eq!(mapper.find_column(7), 4);
+    }
 }
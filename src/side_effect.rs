@@ -5,17 +5,19 @@ use std::{
 
 use chumsky::prelude::Simple;
 
-use crate::{parser::LineMapper, value::Function};
+use crate::{diagnostic, value::Function, vm::RuntimeError};
 
 /// The side effect handlers performed by VM.
 pub(crate) trait SideEffectHandler {
     fn compile_error(
         &mut self,
         file_name: &str,
+        source: &str,
         errors: Vec<Simple<char>>,
-        mapper: &LineMapper,
     ) -> io::Result<()>;
 
+    fn runtime_error(&mut self, error: &RuntimeError) -> io::Result<()>;
+
     fn call_function(&mut self, function: &Function) -> io::Result<()>;
 
     fn print(&mut self, value: &dyn Display) -> io::Result<()>;
@@ -29,22 +31,21 @@ pub(crate) struct PrintAllHandler<'stdout, 'stderr> {
 impl SideEffectHandler for PrintAllHandler<'_, '_> {
     fn compile_error(
         &mut self,
-        _file_name: &str,
+        file_name: &str,
+        source: &str,
         errors: Vec<Simple<char>>,
-        mapper: &LineMapper,
     ) -> io::Result<()> {
         for error in errors.iter() {
-            writeln!(
-                self.stderr,
-                "error at line {}: {}",
-                mapper.find(error.span().start),
-                error
-            )?;
+            diagnostic::write_parse_error(file_name, source, error, true, self.stderr)?;
         }
 
         Ok(())
     }
 
+    fn runtime_error(&mut self, error: &RuntimeError) -> io::Result<()> {
+        writeln!(self.stderr, "{}", error)
+    }
+
     fn call_function(&mut self, function: &Function) -> io::Result<()> {
         function.chunk().write(function.name(), self.stdout)
     }
@@ -1,109 +1,123 @@
 use std::{
-    ptr::{addr_of, addr_of_mut, NonNull},
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    ptr::NonNull,
     rc::Rc,
 };
 
 use crate::{
-    allocator::LEAKING_ALLOCATOR,
+    allocator::{Heap, Trace, Tracer},
     constant::{self, Constant},
     opcode::Chunk,
+    vm::StackFrame,
 };
 
-const STACK_SIZE: usize = 1024;
-
-#[derive(Clone)]
-pub(crate) struct Stack {
-    /// The value stack.
+/// A call frame of a function.
+///
+/// Each frame is a separate heap object, and refers to the caller through the return continuation in slot 0.
+pub(crate) struct Frame {
+    /// The value stack of this frame.
     ///
-    /// # Invariant
-    /// values must be initialized and has STACK_SIZE valid elements indefinitely.
-    /// TODO: GC will destory and reclaim the stack once implemented.
-    values: NonNull<[Option<Value>]>,
-    /// The index at the past one after the end of stack.
-    sp: usize,
-    /// The starting point of the current function in the stack.
-    fp: usize,
+    /// Slot 0 holds the return continuation, followed by the arguments, the locals and the temporaries.
+    /// The frames called by `reset` and the initial frame have nil in slot 0.
+    values: Vec<Value>,
+    /// The head pointer of the list of the open upvalues pointing to this frame.
+    open_upvalues_head: Option<NonNull<Upvalue>>,
+    /// True if this frame may be resumed by a captured continuation.
+    ///
+    /// A shared frame is never modified. Instead, it is copied when a continuation resumes it.
+    shared: bool,
 }
 
-impl Stack {
-    fn empty() -> Self {
+impl Frame {
+    fn new(values: Vec<Value>) -> Self {
         Self {
-            values: LEAKING_ALLOCATOR.alloc_array(None, STACK_SIZE),
-            sp: 0,
-            fp: 0,
-        }
-    }
-
-    fn check(&self) {
-        assert!(self.sp < STACK_SIZE);
-        assert!(self.fp <= self.sp);
-
-        #[cfg(debug_assertions)]
-        {
-            // SAFETY: self.values is initialized.
-            unsafe {
-                for idx in 0..self.values.len() {
-                    let value = self.values.get_unchecked_mut(idx);
-                    assert_eq!(idx < self.sp, value.as_ref().is_some())
-                }
-            }
+            values,
+            open_upvalues_head: None,
+            shared: false,
         }
     }
 
     pub(crate) fn sp(&self) -> usize {
-        self.sp
+        self.values.len()
     }
 
     pub(crate) fn push(&mut self, value: Value) {
-        self.check();
-        // TODO: stack overflow
-        // SAFETY: self.check() ensures that self.sp points to inside the stack,
-        // so it's safe to dereference and assign to it.
-        unsafe {
-            *self.values.get_unchecked_mut(self.sp).as_mut() = Some(value);
+        self.values.push(value);
+    }
+
+    /// Read the value of the slot, following the upvalue if the slot is boxed.
+    fn unbox(slot: &Value) -> Value {
+        match slot {
+            // The slot is moved to the upvalue when the frame was copied.
+            // SAFETY: the upvalues stored in the frame are valid.
+            Value::Upvalue(upvalue) => unsafe { upvalue.as_ref().get() },
+            value => value.clone(),
         }
-        self.sp += 1;
     }
 
     pub(crate) fn pop(&mut self) -> Option<Value> {
-        self.check();
-        // TODO: negative overflow
-        self.sp -= 1;
-        // SAFETY: self.check() ensures that self.sp points to inside the stack,
-        // so it's safe to dereference and assign to it.
-        unsafe { std::mem::replace(self.values.get_unchecked_mut(self.sp).as_mut(), None) }
+        self.values.pop().map(|slot| Self::unbox(&slot))
     }
 
-    fn replace_at(&mut self, index: usize, value: Value) -> Value {
-        self.check();
-        assert!(index < self.sp);
-        // SAFETY: self.check() ensures that self.sp points to inside the stack,
-        // and index is less than self.sp, so we can dereference at index.
-        unsafe {
-            let place = self.values.get_unchecked_mut(index).as_mut();
-            std::mem::replace(place, Some(value)).unwrap()
-        }
+    /// Get the value at `distance` slots below the top of the stack.
+    pub(crate) fn peek(&self, distance: usize) -> Value {
+        Self::unbox(&self.values[self.values.len() - distance - 1])
     }
 
-    fn get_local_ptr(&self, offset: u8) -> NonNull<Option<Value>> {
-        self.check();
+    pub(crate) fn get_local(&self, offset: u8) -> Value {
+        Self::unbox(&self.values[usize::from(offset)])
+    }
 
-        let index = self.fp + usize::from(offset);
-        assert!(index < self.sp);
+    pub(crate) fn set_local(&mut self, offset: u8, value: Value) {
+        match &mut self.values[usize::from(offset)] {
+            // SAFETY: the upvalues stored in the frame are valid.
+            Value::Upvalue(upvalue) => unsafe { upvalue.as_mut().set(value) },
+            slot => *slot = value,
+        }
+    }
 
-        // SAFETY: self.check() ensures that self.sp points to inside the stack,
-        // and index is less than self.sp, so we can point to the index.
-        unsafe { self.values.get_unchecked_mut(index) }
+    /// Replace the value at `distance` slots below the top of the stack.
+    pub(crate) fn replace(&mut self, distance: usize, value: Value) {
+        let index = self.values.len() - distance - 1;
+        self.values[index] = value;
     }
 
-    pub(crate) fn get_local(&self, offset: u8) -> Value {
-        // SAFETY: self.get_local_ptr() returns a pointer to a valid stack slot.
-        unsafe { self.get_local_ptr(offset).as_ref().clone().unwrap() }
+    /// Move every slot but the return continuation to an upvalue, leaving the pointers to the upvalues in the slots.
+    ///
+    /// Since the slots refer to the variables through the upvalues after this,
+    /// the copies of this frame share the variables with each other and with the closures,
+    /// whether or not a closure captures them.
+    /// The temporaries are boxed as well, but they are only read and are unboxed when popped.
+    fn box_slots(&mut self, heap: &mut Heap) {
+        while let Some(head) = self.open_upvalues_head {
+            // SAFETY: the open_upvalues_head must point to a valid upvalue.
+            unsafe {
+                let head = head.as_ptr();
+                let slot = &mut self.values[(*head).index];
+                let value = std::mem::replace(slot, Value::Upvalue(NonNull::new_unchecked(head)));
+                (*head).close(value);
+                self.open_upvalues_head = (*head).next.take();
+            }
+        }
+
+        for slot in self.values.iter_mut().skip(1) {
+            if !matches!(slot, Value::Upvalue(_)) {
+                let value = std::mem::replace(slot, Value::Nil);
+                *slot = Value::Upvalue(heap.alloc(Upvalue::boxed(value)));
+            }
+        }
     }
+}
 
-    pub(crate) fn set_local(&mut self, offset: u8, value: Value) {
-        self.check();
-        self.replace_at(self.fp + usize::from(offset), value);
+impl Trace for Frame {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.values.iter() {
+            value.trace(tracer);
+        }
+        if let Some(head) = self.open_upvalues_head {
+            tracer.mark(head);
+        }
     }
 }
 
@@ -112,15 +126,21 @@ pub(crate) struct Continuation {
     /// The closure to execute.
     ///
     /// # Invariant
-    /// The closure must be valid indefinitely.
-    /// TODO: GC will destory and reclaim the closure once implemented.
+    /// The closure must be valid while this continuation is reachable from the roots of the heap.
     closure: NonNull<Closure>,
     /// The instruction pointer.
     ip: usize,
-    /// The value stack
-    stack: Stack,
-    /// The head pointer of the list of the open upvalues.
-    open_upvalues_head: Option<NonNull<Upvalue>>,
+    /// The call frame.
+    ///
+    /// # Invariant
+    /// The frame must be valid while this continuation is reachable from the roots of the heap,
+    /// and the frame of the running continuation is not shared.
+    frame: NonNull<Frame>,
+    /// The continuation to return to when the function called by the innermost `reset` returns.
+    ///
+    /// The continuation has the continuation of the next `reset` in turn.
+    /// This is ignored for the return continuations stored in frames.
+    reset: Option<NonNull<Continuation>>,
 }
 
 impl Continuation {
@@ -128,12 +148,14 @@ impl Continuation {
     ///
     /// # Safety
     /// The given closure must be valid which is the assumption of the rest of methods.
-    pub(crate) unsafe fn initial(closure: NonNull<Closure>) -> Self {
+    pub(crate) unsafe fn initial(heap: &mut Heap, closure: NonNull<Closure>) -> Self {
+        // The initial code has no return continuation, but reserve slot 0 as other functions.
+        let frame = heap.alloc(Frame::new(vec![Value::Nil]));
         Self {
             closure,
-            stack: Stack::empty(),
             ip: 0,
-            open_upvalues_head: None,
+            frame,
+            reset: None,
         }
     }
 
@@ -146,12 +168,18 @@ impl Continuation {
         &self.closure().function
     }
 
-    fn chunk(&self) -> &Chunk {
+    pub(crate) fn chunk(&self) -> &Chunk {
         &self.function().chunk
     }
 
-    pub(crate) fn stack_mut(&mut self) -> &mut Stack {
-        &mut self.stack
+    fn frame(&self) -> &Frame {
+        // SAFETY: the frame is valid.
+        unsafe { self.frame.as_ref() }
+    }
+
+    pub(crate) fn frame_mut(&mut self) -> &mut Frame {
+        // SAFETY: the frame is valid, and the frame of the running continuation is not shared with others.
+        unsafe { self.frame.as_mut() }
     }
 
     pub(crate) fn code(&self, increment: usize) -> u8 {
@@ -162,6 +190,11 @@ impl Continuation {
         self.code(0)
     }
 
+    /// Read the 16-bit operand of a jump instruction.
+    pub(crate) fn jump_offset(&self) -> usize {
+        usize::from(u16::from_be_bytes([self.code(1), self.code(2)]))
+    }
+
     pub(crate) fn constant(&self, index: u8) -> &Constant {
         &self.chunk().constants()[usize::from(index)]
     }
@@ -170,111 +203,377 @@ impl Continuation {
         self.ip >= self.chunk().code().len()
     }
 
+    pub(crate) fn ip(&self) -> usize {
+        self.ip
+    }
+
     pub(crate) fn advance(&mut self, increment: usize) {
         self.ip += increment;
     }
 
+    pub(crate) fn retreat(&mut self, decrement: usize) {
+        self.ip -= decrement;
+    }
+
     pub(crate) fn display(&self) -> String {
-        format!(
-            "ip = {}, sp = {}, fp = {}",
-            self.ip, self.stack.sp, self.stack.fp
-        )
+        format!("ip = {}, sp = {}", self.ip, self.frame().sp())
     }
 
-    /// Call a function on the top of the stack.
-    pub(crate) fn call(&mut self, arguments_len: u8) -> NonNull<Closure> {
-        // NOTE: the stack pointer of the return_continuation is invalid when we return from the function.
-        // But, perform_return() adjust it when we actually return to the callee.
-        let return_continuation = Value::Return(self.clone());
-        let callee_index = self.stack.sp - usize::from(arguments_len) - 1;
-        let callee = self.stack.replace_at(callee_index, return_continuation);
-        let closure = match callee {
-            Value::Function(function) => LEAKING_ALLOCATOR.alloc(Closure::free(function)),
-            Value::Closure(closure) => closure,
-            _ => todo!("callee is not a function nor a closure"),
-        };
+    /// Build the stack trace from this continuation failing at the offset of the current chunk.
+    ///
+    /// The callers are found by following the return continuations in slot 0,
+    /// and the callers of `reset` by following the chain of `reset`.
+    pub(crate) fn stack_trace(&self, offset: usize) -> Vec<StackFrame> {
+        let mut trace = vec![StackFrame {
+            function: self.function().name.clone(),
+            line: self.chunk().line(offset),
+        }];
+
+        let mut current = self.clone();
+        loop {
+            current = match &current.frame().values[0] {
+                Value::Return(continuation) => Continuation {
+                    reset: current.reset,
+                    ..continuation.clone()
+                },
+                _ => match current.reset {
+                    // SAFETY: the continuations of reset are valid.
+                    Some(reset) => unsafe { reset.as_ref().clone() },
+                    None => break,
+                },
+            };
+            // The continuation resumes after the call, so the instruction before it is the call.
+            trace.push(StackFrame {
+                function: current.function().name.clone(),
+                line: current.chunk().line(current.ip - 1),
+            });
+        }
+
+        trace
+    }
+
+    /// Start running the closure in a new frame.
+    ///
+    /// `values` are the initial slots of the frame, which are the return continuation and the arguments
+    /// (preceded by the receiver for methods).
+    fn enter(
+        &mut self,
+        heap: &mut Heap,
+        callee: Value,
+        values: Vec<Value>,
+        arguments_len: usize,
+    ) -> Result<NonNull<Closure>, String> {
+        let closure = Closure::from_callee(heap, callee, arguments_len)?;
 
         // Jump to the start of the given chunk.
         self.closure = closure;
         self.ip = 0;
-        // Shift the frame pointer (stack pointer remains same).
-        self.stack.fp = callee_index;
+        self.frame = heap.alloc(Frame::new(values));
+
+        Ok(closure)
+    }
+
+    /// Jump to the start of the function passed to `callcc`, `reset` or `shift`, named `name`.
+    ///
+    /// A bound method is called with its receiver in slot 1 as `this`, as `call_method` does.
+    /// Any other callee, such as a builtin, a class or a continuation, is rejected.
+    fn enter_function(
+        &mut self,
+        heap: &mut Heap,
+        name: &str,
+        function: Value,
+        mut values: Vec<Value>,
+        arguments_len: usize,
+    ) -> Result<NonNull<Closure>, String> {
+        match function {
+            // SAFETY: the bound methods in the values are valid.
+            Value::BoundMethod(bound) => unsafe {
+                let bound = bound.as_ref();
+                values.insert(1, bound.receiver());
+                self.enter(heap, bound.method(), values, arguments_len)
+            },
+            Value::Function(_) | Value::Closure(_) => {
+                self.enter(heap, function, values, arguments_len)
+            }
+            _ => Err(format!(
+                "{} expects a function but got {}",
+                name,
+                function.type_name()
+            )),
+        }
+    }
 
-        closure
+    /// Pop the callee and the arguments of OP_CALL.
+    fn pop_call(&mut self, arguments_len: u8) -> (Value, Vec<Value>) {
+        let frame = self.frame_mut();
+        let callee_index = frame.sp() - usize::from(arguments_len) - 1;
+        let arguments = frame
+            .values
+            .split_off(callee_index + 1)
+            .iter()
+            .map(Frame::unbox)
+            .collect();
+        let callee = frame.pop().unwrap();
+        (callee, arguments)
+    }
+
+    /// Mark the frame and the frame of the innermost `reset` as shared,
+    /// so that they are copied before modified.
+    ///
+    /// The rest of the frames reachable from this continuation are marked lazily when copied.
+    fn share(&mut self) {
+        self.frame_mut().shared = true;
+        if let Some(mut reset) = self.reset {
+            // SAFETY: the continuations of reset are valid.
+            unsafe { reset.as_mut().frame_mut().shared = true };
+        }
+    }
+
+    /// Copy the frame if it's shared with captured continuations, so that we can modify it.
+    ///
+    /// Like Scheme, the locals are variables shared by every copy rather than values copied per resumption,
+    /// while each copy gets its own temporaries.
+    fn thaw(&mut self, heap: &mut Heap) {
+        // SAFETY: the frame is valid.
+        let frame = unsafe { self.frame.as_mut() };
+        if !frame.shared {
+            return;
+        }
+
+        // The variables must be shared between the frame and the copy,
+        // so that the assignments after resuming are visible to the other resumptions.
+        frame.box_slots(heap);
+        self.frame = heap.alloc(Frame::new(frame.values.clone()));
+
+        // Now the frames which both the frame and the copy return to are shared.
+        if let Value::Return(continuation) = &mut self.frame_mut().values[0] {
+            continuation.frame_mut().shared = true;
+        }
+        if let Some(mut reset) = self.reset {
+            // SAFETY: the continuations of reset are valid.
+            unsafe { reset.as_mut().frame_mut().shared = true };
+        }
+    }
+
+    /// Get the callee of the OP_CALL taking the given number of arguments.
+    pub(crate) fn callee(&self, arguments_len: u8) -> Value {
+        self.frame().peek(usize::from(arguments_len))
+    }
+
+    /// Call a function on the top of the stack.
+    pub(crate) fn call(
+        &mut self,
+        heap: &mut Heap,
+        arguments_len: u8,
+    ) -> Result<NonNull<Closure>, String> {
+        let (callee, arguments) = self.pop_call(arguments_len);
+
+        // The return value will be pushed in place of the callee.
+        let mut values = vec![Value::Return(self.clone())];
+        values.extend(arguments);
+        self.enter(heap, callee, values, usize::from(arguments_len))
+    }
+
+    /// Call the method with the receiver and the arguments on the top of the stack.
+    ///
+    /// The receiver is passed in slot 1 as `this`, followed by the arguments.
+    pub(crate) fn call_method(
+        &mut self,
+        heap: &mut Heap,
+        receiver: Value,
+        method: Value,
+        arguments_len: u8,
+    ) -> Result<NonNull<Closure>, String> {
+        let (_, arguments) = self.pop_call(arguments_len);
+
+        let mut values = vec![Value::Return(self.clone()), receiver];
+        values.extend(arguments);
+        self.enter(heap, method, values, usize::from(arguments_len))
+    }
+
+    /// Call a function on the top of the stack without arguments,
+    /// delimiting the continuations captured by `shift` inside it (`reset(f)`).
+    pub(crate) fn reset(&mut self, heap: &mut Heap) -> Result<NonNull<Closure>, String> {
+        let function = self.frame_mut().pop().unwrap();
+
+        let outer_reset = self.reset;
+        let return_continuation = self.clone();
+        self.reset = Some(heap.alloc(return_continuation));
+        let result = self.enter_function(heap, "reset", function, vec![Value::Nil], 0);
+        if result.is_err() {
+            // Leave the caller as is, so that it appears only once in the stack trace.
+            self.reset = outer_reset;
+        }
+        result
+    }
+
+    /// Call the function on the top of the stack with the current continuation (`callcc(f)`).
+    ///
+    /// The stack looks like `[.., callcc, f]`, and `f` is called with `k` in place of `callcc`.
+    /// When `k` is resumed, the resumed value is placed at the slot of `callcc`
+    /// as if `f` returned the value.
+    pub(crate) fn call_with_current_continuation(
+        &mut self,
+        heap: &mut Heap,
+        arguments_len: u8,
+    ) -> Result<NonNull<Closure>, String> {
+        if arguments_len != 1 {
+            let callcc = Value::Builtin(Builtin::CallCc);
+            return Err(callcc.arity_error(1, usize::from(arguments_len)));
+        }
+
+        let (_, mut arguments) = self.pop_call(arguments_len);
+        let function = arguments.pop().unwrap();
+
+        self.share();
+        let continuation = heap.alloc(CapturedContinuation {
+            continuation: self.clone(),
+            delimited: false,
+        });
+
+        let values = vec![
+            Value::Return(self.clone()),
+            Value::Continuation(continuation),
+        ];
+        self.enter_function(heap, "callcc", function, values, 1)
+    }
+
+    /// Capture the continuation up to the innermost `reset` and call the function on the top of the stack with it (`shift(f)`).
+    ///
+    /// The frames up to the `reset` are dropped from the running continuation.
+    /// Then, `f` is called in place of the function called by `reset`, so `f` returns to the caller of `reset`.
+    pub(crate) fn shift(&mut self, heap: &mut Heap) -> Result<NonNull<Closure>, String> {
+        if self.reset.is_none() {
+            return Err("shift must be called inside reset".into());
+        }
+        let function = self.frame_mut().pop().unwrap();
+
+        self.frame_mut().shared = true;
+        let continuation = heap.alloc(CapturedContinuation {
+            continuation: self.clone(),
+            delimited: true,
+        });
+
+        self.enter_function(
+            heap,
+            "shift",
+            function,
+            vec![Value::Nil, Value::Continuation(continuation)],
+            1,
+        )
+    }
+
+    /// Resume the continuation captured by `callcc` or `shift`.
+    ///
+    /// For `callcc`, the current continuation is abandoned.
+    /// For `shift`, the captured frames run as if they are called by `reset` here,
+    /// so the continuation returns to this caller.
+    /// In both cases, the argument (or nil if not given) becomes the result of `callcc` or `shift`.
+    ///
+    /// Since the captured frames are copied before modified, a continuation can be resumed any number of times.
+    /// The locals are shared by all the resumptions as in Scheme, so an assignment made after one resumption
+    /// is seen by the later ones. Only the temporaries, such as the pending arguments of a call, are per resumption.
+    pub(crate) fn resume(
+        &mut self,
+        heap: &mut Heap,
+        captured: NonNull<CapturedContinuation>,
+        arguments_len: u8,
+    ) -> Result<(), String> {
+        let value = match arguments_len {
+            0 => Value::Nil,
+            1 => self.frame_mut().pop().unwrap(),
+            _ => {
+                return Err(format!(
+                    "a continuation expects at most 1 argument but got {}",
+                    arguments_len
+                ))
+            }
+        };
+        self.frame_mut().pop().unwrap();
+        // SAFETY: the captured continuation was the callee in the running frame at the start of this step,
+        // and the garbage collector only runs between steps, so it's not reclaimed yet.
+        let captured = unsafe { captured.as_ref() };
+
+        let mut continuation = captured.continuation.clone();
+        if captured.delimited {
+            let return_continuation = self.clone();
+            continuation.reset = Some(heap.alloc(return_continuation));
+        }
+        *self = continuation;
+        self.thaw(heap);
+        self.frame_mut().push(value);
+        Ok(())
     }
 
     /// Run the return procedure.
-    pub(crate) fn perform_return(&mut self) {
-        let fp = self.stack.fp;
-        let return_value = self.stack.pop().unwrap();
-        let continuation = self.stack.get_local(0);
-
-        // Drop the call frame for this function and close upvalues pointing to the inside of it.
-        self.close_upvalue(fp);
-
-        match continuation {
-            Value::Return(mut continuation) => {
-                // Since the return continuation's sp is outdated, we fix it here.
-                // TODO: Isn't this assuming that the caller and the callee share the stack? Is this a valid assumption?
-                continuation.stack.sp = self.stack.sp;
-                continuation.stack.push(return_value);
+    pub(crate) fn perform_return(&mut self, heap: &mut Heap) -> Result<(), String> {
+        let reset = self.reset;
+        if !matches!(self.frame().values[0], Value::Return(_)) && reset.is_none() {
+            return Err("cannot return from the initial code".into());
+        }
+
+        let return_value = self.frame_mut().pop().unwrap();
+        let return_continuation = std::mem::replace(&mut self.frame_mut().values[0], Value::Nil);
+
+        // Drop the frame of this function and close upvalues pointing to the inside of it.
+        self.close_upvalue(0);
+
+        match return_continuation {
+            Value::Return(continuation) => {
                 *self = continuation;
+                self.reset = reset;
+            }
+            _ => {
+                // The function called by reset returns to the caller of reset.
+                let reset = reset.unwrap();
+                // SAFETY: the continuations of reset are valid.
+                *self = unsafe { reset.as_ref().clone() };
             }
-            _ => todo!("The return continuation is not a continuation"),
         }
+
+        self.thaw(heap);
+        self.frame_mut().push(return_value);
+        Ok(())
     }
 
-    /// Get the pointer to the object held by the current function's upvalue at the index.
-    fn get_upvalue_value_ptr(&self, index: u8) -> NonNull<Option<Value>> {
-        let index = usize::from(index);
-        // TODO: the assumption of safety is that the upvalues stored in the closure are valid,
-        // and the index is in-bounds.
-        unsafe {
-            let closure = self.closure();
-            assert!(index < closure.function.upvalues);
-            let upvalues = closure.upvalues().get_unchecked_mut(index);
-            upvalues.as_ref().as_ref().pointer
-        }
+    fn get_upvalue_ptr(&self, index: u8) -> NonNull<Upvalue> {
+        self.closure().upvalues()[usize::from(index)]
     }
 
     pub(crate) fn get_upvalue(&self, index: u8) -> Value {
         // TODO: the assumption of safety is that the upvalues stored in the closure are valid,
         // and the index is in-bounds.
-        unsafe {
-            self.get_upvalue_value_ptr(index)
-                .as_ref()
-                .as_ref()
-                .unwrap()
-                .clone()
-        }
+        unsafe { self.get_upvalue_ptr(index).as_ref().get() }
     }
 
     pub(crate) fn set_upvalue(&mut self, index: u8, value: Value) {
-        let mut pointer = self.get_upvalue_value_ptr(index);
         // TODO: the assumption of safety is that the upvalues stored in the closure are valid,
         // and the index is in-bounds.
-        unsafe {
-            *pointer.as_mut() = Some(value);
-        }
+        unsafe { self.get_upvalue_ptr(index).as_mut().set(value) }
     }
 
-    /// Get or create upvalue pointing to the stack.
+    /// Get or create upvalue pointing to the frame.
     ///
-    /// The upvalues are sorted by the order of the pointer to the stack slot
-    /// (the greatest one comes first).
-    /// When an upvalue with the same stack index is found, returns it.
+    /// The upvalues are sorted by the slot index (the greatest one comes first).
+    /// When an upvalue with the same slot index is found, returns it.
     /// When not found, a new upvalue is inserted into the appropriate place and returned.
-    fn get_or_create_upvalue_to_stack(&mut self, index: u8) -> NonNull<Upvalue> {
-        let pointer = self.stack.get_local_ptr(index);
+    fn get_or_create_upvalue_to_frame(&mut self, heap: &mut Heap, index: u8) -> NonNull<Upvalue> {
+        let frame_ptr = self.frame;
+        let frame = self.frame_mut();
+        let index = usize::from(index);
+
+        if let Value::Upvalue(upvalue) = frame.values[index] {
+            return upvalue;
+        }
 
         let mut prev = None;
-        let mut current = self.open_upvalues_head;
+        let mut current = frame.open_upvalues_head;
 
         while let Some(current_ptr) = current {
             // SAFETY: the upvalues in the open-upvalues list are valid
             unsafe {
                 let current_ref = current_ptr.as_ref();
-                match current_ref.pointer.cmp(&pointer) {
+                match current_ref.index.cmp(&index) {
                     std::cmp::Ordering::Less => break,
                     std::cmp::Ordering::Equal => return current_ptr,
                     std::cmp::Ordering::Greater => {
@@ -285,22 +584,22 @@ impl Continuation {
             }
         }
 
-        let new_upvalue = LEAKING_ALLOCATOR.alloc(Upvalue::open(current, pointer));
+        let new_upvalue = heap.alloc(Upvalue::open(current, frame_ptr, index));
         match prev {
             // SAFETY: the upvalues in the open-upvalues list are valid
             Some(mut prev) => unsafe {
                 prev.as_mut().next = Some(new_upvalue);
             },
             None => {
-                self.open_upvalues_head = Some(new_upvalue);
+                frame.open_upvalues_head = Some(new_upvalue);
             }
         }
         new_upvalue
     }
 
     /// Create a closure on stack.
-    pub(crate) fn perform_closure(&mut self) {
-        let function = match self.stack.pop().unwrap() {
+    pub(crate) fn perform_closure(&mut self, heap: &mut Heap) {
+        let function = match self.frame_mut().pop().unwrap() {
             Value::Function(function) => function,
             _ => todo!("type error: OP_CLOSURE takes function"),
         };
@@ -311,53 +610,35 @@ impl Continuation {
                 let is_local = self.code(2 + 2 * idx) > 0;
                 let index = self.code(2 + 2 * idx + 1);
                 if is_local {
-                    self.get_or_create_upvalue_to_stack(index)
+                    self.get_or_create_upvalue_to_frame(heap, index)
                 } else {
-                    // TODO: assuming the upvalues are all valid.
-                    unsafe {
-                        // this closure must be valid.
-                        let closure = self.closure.as_ref();
-                        let index = usize::from(index);
-                        assert!(index < closure.upvalues.len());
-                        *closure.upvalues.get_unchecked_mut(index).as_ptr()
-                    }
+                    self.get_upvalue_ptr(index)
                 }
             })
             .collect();
-        // SAFETY: the pointer is valid.
-        let upvalues = unsafe { NonNull::new_unchecked(Box::into_raw(upvalues)) };
-        let closure =
-            Value::Closure(LEAKING_ALLOCATOR.alloc(Closure::capturing(function, upvalues)));
-        self.stack.push(closure);
+        let closure = Value::Closure(heap.alloc(Closure::capturing(function, upvalues)));
+        self.frame_mut().push(closure);
         self.advance(2 + 2 * upvalues_len);
     }
 
+    /// Drop the slots at or above `new_sp`, closing the upvalues pointing to them.
     pub(crate) fn close_upvalue(&mut self, new_sp: usize) {
-        self.stack.check();
-        assert!(new_sp < self.stack.sp);
+        let frame = self.frame_mut();
+        assert!(new_sp <= frame.sp());
 
-        for index in (new_sp..self.stack.sp).rev() {
-            // SAFETY: index is a valid stack slot, and the open_upvalues_head must point to a valid upvalue.
-            unsafe {
-                let mut pointer = self.stack.values.get_unchecked_mut(index);
-                let value = std::mem::replace(pointer.as_mut(), None).unwrap();
+        for index in (new_sp..frame.sp()).rev() {
+            let value = frame.values.pop().unwrap();
 
-                if let Some(head) = self.open_upvalues_head {
+            if let Some(head) = frame.open_upvalues_head {
+                // SAFETY: the open_upvalues_head must point to a valid upvalue.
+                unsafe {
                     let head = head.as_ptr();
-                    match addr_of!((*head).pointer).read().cmp(&pointer) {
+                    match (*head).index.cmp(&index) {
                         std::cmp::Ordering::Less => {}
                         std::cmp::Ordering::Equal => {
-                            let pointer_to_closed = addr_of_mut!((*head).closed);
-                            // write value to closed
-                            assert!(
-                                std::mem::replace(&mut *pointer_to_closed, Some(value)).is_none()
-                            );
-                            // update pointer to point to its closed
-                            addr_of_mut!((*head).pointer)
-                                .write(NonNull::new_unchecked(pointer_to_closed));
+                            (*head).close(value);
                             // unlink the upvalue and update head
-                            let next = std::mem::replace(&mut *addr_of_mut!((*head).next), None);
-                            self.open_upvalues_head = next;
+                            frame.open_upvalues_head = (*head).next.take();
                         }
                         std::cmp::Ordering::Greater => {
                             unreachable!("open_upvalues_head must point to a valid stack slot.")
@@ -366,11 +647,16 @@ impl Continuation {
                 }
             }
         }
+    }
+}
 
-        // rewind the sp
-        self.stack.sp = new_sp;
-
-        self.stack.check();
+impl Trace for Continuation {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.closure);
+        tracer.mark(self.frame);
+        if let Some(reset) = self.reset {
+            tracer.mark(reset);
+        }
     }
 }
 
@@ -378,6 +664,8 @@ impl Continuation {
 #[derive(Clone)]
 pub(crate) struct Function {
     name: String,
+    /// The number of parameters.
+    arity: usize,
     chunk: Rc<Chunk>,
     /// The number of upvalues.
     upvalues: usize,
@@ -385,14 +673,20 @@ pub(crate) struct Function {
 
 impl From<constant::Function> for Function {
     fn from(function: constant::Function) -> Self {
-        Self::new(function.name, function.chunk, function.upvalues)
+        Self::new(
+            function.name,
+            function.arity,
+            function.chunk,
+            function.upvalues,
+        )
     }
 }
 
 impl Function {
-    pub(crate) fn new(name: String, chunk: Rc<Chunk>, upvalues: usize) -> Self {
+    pub(crate) fn new(name: String, arity: usize, chunk: Rc<Chunk>, upvalues: usize) -> Self {
         Self {
             name,
+            arity,
             chunk,
             upvalues,
         }
@@ -409,37 +703,79 @@ impl Function {
 
 /// The run-time representation of upvalues.
 pub(crate) struct Upvalue {
-    /// The pointer to the next upvalue.
+    /// The pointer to the next open upvalue of the same frame.
     ///
-    /// The next upvalue must point to a slot in the same stack that has smaller index than this (next.pointer < pointer).
+    /// The next upvalue must point to a slot that has smaller index than this (next.index < index).
     next: Option<NonNull<Upvalue>>,
-    /// The pointer to the pointed value.
-    ///
-    /// It points to either a slot in a stack or closed of self.
-    /// TODO: is it okay to use self-referential pointer?
-    pointer: NonNull<Option<Value>>,
+    /// The frame holding the variable while this upvalue is open.
+    frame: Option<NonNull<Frame>>,
+    /// The slot index of the variable in the frame.
+    index: usize,
     /// The place to store the closed upvalue.
     closed: Option<Value>,
 }
 
 impl Upvalue {
     /// Create a new open upvalue.
-    fn open(next: Option<NonNull<Upvalue>>, pointer: NonNull<Option<Value>>) -> Self {
+    fn open(next: Option<NonNull<Upvalue>>, frame: NonNull<Frame>, index: usize) -> Self {
         Self {
             next,
-            pointer,
+            frame: Some(frame),
+            index,
             closed: None,
         }
     }
 
-    // fn is_closed(&self) -> bool {
-    //     self.closed.is_none()
-    // }
+    /// Create a closed upvalue holding the value.
+    fn boxed(value: Value) -> Self {
+        Self {
+            next: None,
+            frame: None,
+            index: 0,
+            closed: Some(value),
+        }
+    }
+
+    /// Move the variable from the frame to this upvalue.
+    fn close(&mut self, value: Value) {
+        assert!(self.closed.replace(value).is_none());
+        self.frame = None;
+    }
+
+    fn get(&self) -> Value {
+        match self.frame {
+            // SAFETY: the frame of an open upvalue is valid.
+            Some(frame) => unsafe { frame.as_ref().values[self.index].clone() },
+            None => self.closed.clone().unwrap(),
+        }
+    }
+
+    fn set(&mut self, value: Value) {
+        match self.frame {
+            // SAFETY: the frame of an open upvalue is valid.
+            Some(mut frame) => unsafe { frame.as_mut().values[self.index] = value },
+            None => self.closed = Some(value),
+        }
+    }
+}
+
+impl Trace for Upvalue {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(next) = self.next {
+            tracer.mark(next);
+        }
+        if let Some(frame) = self.frame {
+            tracer.mark(frame);
+        }
+        if let Some(closed) = &self.closed {
+            closed.trace(tracer);
+        }
+    }
 }
 
 pub(crate) struct Closure {
     function: Function,
-    upvalues: NonNull<[NonNull<Upvalue>]>,
+    upvalues: Box<[NonNull<Upvalue>]>,
 }
 
 impl Closure {
@@ -449,20 +785,467 @@ impl Closure {
 
         Self {
             function,
-            upvalues: LEAKING_ALLOCATOR.alloc_empty_array(),
+            upvalues: Box::new([]),
         }
     }
 
-    pub(crate) fn capturing(function: Function, upvalues: NonNull<[NonNull<Upvalue>]>) -> Self {
+    pub(crate) fn capturing(function: Function, upvalues: Box<[NonNull<Upvalue>]>) -> Self {
         Self { function, upvalues }
     }
 
+    /// Get the closure to run for the callee value called with the given number of arguments.
+    fn from_callee(
+        heap: &mut Heap,
+        callee: Value,
+        arguments_len: usize,
+    ) -> Result<NonNull<Closure>, String> {
+        let arity = match &callee {
+            Value::Function(function) => function.arity,
+            // SAFETY: the closures in the values are valid.
+            Value::Closure(closure) => unsafe { closure.as_ref().function.arity },
+            _ => return Err(format!("cannot call {}", callee.type_name())),
+        };
+        if arity != arguments_len {
+            return Err(callee.arity_error(arity, arguments_len));
+        }
+
+        match callee {
+            Value::Function(function) => Ok(heap.alloc(Closure::free(function))),
+            Value::Closure(closure) => Ok(closure),
+            _ => unreachable!(),
+        }
+    }
+
     pub(crate) fn function(&self) -> &Function {
         &self.function
     }
 
-    pub(crate) fn upvalues(&self) -> NonNull<[NonNull<Upvalue>]> {
-        self.upvalues
+    pub(crate) fn upvalues(&self) -> &[NonNull<Upvalue>] {
+        &self.upvalues
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        for upvalue in self.upvalues.iter() {
+            tracer.mark(*upvalue);
+        }
+    }
+}
+
+/// The run-time representation of a class.
+pub(crate) struct Class {
+    name: String,
+    /// The functions or the closures of the methods, including the inherited ones.
+    methods: HashMap<String, Value>,
+}
+
+impl Class {
+    pub(crate) fn new(name: String) -> Self {
+        Self {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn method(&self, name: &str) -> Option<Value> {
+        self.methods.get(name).cloned()
+    }
+
+    pub(crate) fn add_method(&mut self, name: String, method: Value) {
+        self.methods.insert(name, method);
+    }
+
+    /// Copy the methods of the superclass, which are overridden by the methods defined later.
+    pub(crate) fn inherit(&mut self, superclass: &Class) {
+        self.methods.extend(
+            superclass
+                .methods
+                .iter()
+                .map(|(name, method)| (name.clone(), method.clone())),
+        );
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, tracer: &mut Tracer) {
+        for method in self.methods.values() {
+            method.trace(tracer);
+        }
+    }
+}
+
+/// An instance of a class, which holds its fields.
+pub(crate) struct Instance {
+    class: NonNull<Class>,
+    fields: HashMap<String, Value>,
+}
+
+impl Instance {
+    pub(crate) fn new(class: NonNull<Class>) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn class(&self) -> NonNull<Class> {
+        self.class
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<Value> {
+        self.fields.get(name).cloned()
+    }
+
+    pub(crate) fn set(&mut self, name: String, value: Value) {
+        self.fields.insert(name, value);
+    }
+}
+
+impl Trace for Instance {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.class);
+        for value in self.fields.values() {
+            value.trace(tracer);
+        }
+    }
+}
+
+/// A method bound to the receiver, created by getting the method as a property.
+pub(crate) struct BoundMethod {
+    receiver: Value,
+    method: Value,
+}
+
+impl BoundMethod {
+    pub(crate) fn new(receiver: Value, method: Value) -> Self {
+        Self { receiver, method }
+    }
+
+    pub(crate) fn receiver(&self) -> Value {
+        self.receiver.clone()
+    }
+
+    pub(crate) fn method(&self) -> Value {
+        self.method.clone()
+    }
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.receiver.trace(tracer);
+        self.method.trace(tracer);
+    }
+}
+
+/// A mutable list of values, shared by all the values referring to it.
+pub(crate) struct List {
+    elements: Vec<Value>,
+}
+
+impl List {
+    pub(crate) fn new(elements: Vec<Value>) -> Self {
+        Self { elements }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Convert the index value to the position in this list.
+    fn position(&self, index: &Value) -> Result<usize, String> {
+        match index {
+            Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && *n < self.len() as f64 => {
+                Ok(*n as usize)
+            }
+            Value::Number(n) if n.fract() == 0.0 => Err(format!(
+                "index {} out of bounds for a list of length {}",
+                n,
+                self.len()
+            )),
+            Value::Number(n) => Err(format!("list index must be an integer, not {}", n)),
+            _ => Err(format!(
+                "list index must be a number, not {}",
+                index.type_name()
+            )),
+        }
+    }
+
+    pub(crate) fn get(&self, index: &Value) -> Result<Value, String> {
+        let position = self.position(index)?;
+        Ok(self.elements[position].clone())
+    }
+
+    pub(crate) fn set(&mut self, index: &Value, value: Value) -> Result<(), String> {
+        let position = self.position(index)?;
+        self.elements[position] = value;
+        Ok(())
+    }
+
+    pub(crate) fn push(&mut self, value: Value) {
+        self.elements.push(value);
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Value> {
+        self.elements.pop()
+    }
+}
+
+impl Trace for List {
+    fn trace(&self, tracer: &mut Tracer) {
+        for element in self.elements.iter() {
+            element.trace(tracer);
+        }
+    }
+}
+
+/// A value used as a key of maps.
+///
+/// The keys are ordered by their types, then by their values for nil, booleans, numbers and strings,
+/// or by their identities for the others, which is consistent with `==`.
+/// The identities are heap addresses, so maps list those keys by insertion instead (see [`Map::ordered_entries`]).
+#[derive(Clone)]
+pub(crate) struct MapKey(Value);
+
+impl MapKey {
+    pub(crate) fn new(value: Value) -> Result<Self, String> {
+        match value {
+            Value::Number(n) if n.is_nan() => Err("NaN cannot be a map key".into()),
+            // -0.0 == 0.0, so they must be the same key.
+            Value::Number(0.0) => Ok(Self(Value::Number(0.0))),
+            Value::Return(_) | Value::Upvalue(_) => {
+                Err(format!("{} cannot be a map key", value.type_name()))
+            }
+            value => Ok(Self(value)),
+        }
+    }
+
+    pub(crate) fn value(&self) -> Value {
+        self.0.clone()
+    }
+
+    /// The order of the types of the keys.
+    fn rank(&self) -> u8 {
+        match self.0 {
+            Value::Nil => 0,
+            Value::Boolean(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Function(_) => 4,
+            Value::Closure(_) => 5,
+            Value::Builtin(_) => 6,
+            Value::Continuation(_) => 7,
+            Value::Class(_) => 8,
+            Value::Instance(_) => 9,
+            Value::BoundMethod(_) => 10,
+            Value::List(_) => 11,
+            Value::Map(_) => 12,
+            Value::Return(_) | Value::Upvalue(_) => unreachable!("rejected by MapKey::new"),
+        }
+    }
+
+    /// Whether the key is compared by its identity rather than its value.
+    fn by_identity(&self) -> bool {
+        !matches!(
+            self.0,
+            Value::Nil | Value::Boolean(_) | Value::Number(_) | Value::String(_)
+        )
+    }
+
+    /// The identity of the key compared by `==` as references.
+    fn identity(&self) -> usize {
+        match &self.0 {
+            Value::Function(function) => Rc::as_ptr(&function.chunk) as usize,
+            Value::Closure(closure) => closure.as_ptr() as usize,
+            Value::Builtin(builtin) => *builtin as usize,
+            Value::Continuation(continuation) => continuation.as_ptr() as usize,
+            Value::Class(class) => class.as_ptr() as usize,
+            Value::Instance(instance) => instance.as_ptr() as usize,
+            Value::BoundMethod(bound) => bound.as_ptr() as usize,
+            Value::List(list) => list.as_ptr() as usize,
+            Value::Map(map) => map.as_ptr() as usize,
+            _ => 0,
+        }
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MapKey {}
+
+impl PartialOrd for MapKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MapKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (&self.0, &other.0) {
+            (Value::Boolean(lhs), Value::Boolean(rhs)) => lhs.cmp(rhs),
+            (Value::Number(lhs), Value::Number(rhs)) => lhs.total_cmp(rhs),
+            (Value::String(lhs), Value::String(rhs)) => lhs.cmp(rhs),
+            _ => (self.rank(), self.identity()).cmp(&(other.rank(), other.identity())),
+        }
+    }
+}
+
+/// A mutable map from keys to values, shared by all the values referring to it.
+pub(crate) struct Map {
+    /// The values with the number of the insertion of their keys.
+    entries: BTreeMap<MapKey, (usize, Value)>,
+    inserted: usize,
+}
+
+impl Map {
+    /// Create a map inserting the entries in order, so that the later entries win.
+    pub(crate) fn new(entries: Vec<(MapKey, Value)>) -> Self {
+        let mut map = Self {
+            entries: BTreeMap::new(),
+            inserted: 0,
+        };
+        for (key, value) in entries {
+            map.set(key, value);
+        }
+        map
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn get(&self, key: &MapKey) -> Result<Value, String> {
+        match self.entries.get(key) {
+            Some((_, value)) => Ok(value.clone()),
+            None => Err(format!("undefined key {}", key.0.display())),
+        }
+    }
+
+    pub(crate) fn contains(&self, key: &MapKey) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Set the value of the key, keeping its place in the order if it already exists.
+    pub(crate) fn set(&mut self, key: MapKey, value: Value) {
+        match self.entries.get_mut(&key) {
+            Some((_, old)) => *old = value,
+            None => {
+                self.entries.insert(key, (self.inserted, value));
+                self.inserted += 1;
+            }
+        }
+    }
+
+    pub(crate) fn delete(&mut self, key: &MapKey) -> Option<Value> {
+        self.entries.remove(key).map(|(_, value)| value)
+    }
+
+    /// The entries in the order of their keys.
+    ///
+    /// Nil, booleans, numbers and strings are in ascending order,
+    /// while the keys of each of the other types are in the order of their insertion.
+    fn ordered_entries(&self) -> Vec<(&MapKey, &Value)> {
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        // The keys of the same type are next to each other, so only reorder them within the type.
+        entries.sort_by(|(lhs, (lhs_inserted, _)), (rhs, (rhs_inserted, _))| {
+            if lhs.by_identity() && lhs.rank() == rhs.rank() {
+                lhs_inserted.cmp(rhs_inserted)
+            } else {
+                lhs.cmp(rhs)
+            }
+        });
+        entries
+            .into_iter()
+            .map(|(key, (_, value))| (key, value))
+            .collect()
+    }
+
+    /// The keys in the order of [`Map::ordered_entries`].
+    pub(crate) fn keys(&self) -> Vec<Value> {
+        self.ordered_entries()
+            .into_iter()
+            .map(|(key, _)| key.value())
+            .collect()
+    }
+}
+
+impl Trace for Map {
+    fn trace(&self, tracer: &mut Tracer) {
+        for (key, (_, value)) in self.entries.iter() {
+            key.0.trace(tracer);
+            value.trace(tracer);
+        }
+    }
+}
+
+/// The continuation captured by `callcc` or `shift`.
+pub(crate) struct CapturedContinuation {
+    /// The continuation at the capture.
+    ///
+    /// For `shift`, the continuation of `reset` is replaced on resumption.
+    continuation: Continuation,
+    /// True if captured by `shift`.
+    delimited: bool,
+}
+
+impl Trace for CapturedContinuation {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.continuation.trace(tracer);
+    }
+}
+
+/// The functions implemented by the VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Builtin {
+    /// `callcc(f)` calls `f` with the current continuation.
+    CallCc,
+    /// `len(xs)` returns the number of the elements of a list, the characters of a string, or the entries of a map.
+    Len,
+    /// `push(xs, x)` appends `x` to the end of the list.
+    Push,
+    /// `pop(xs)` removes the last element of the list and returns it.
+    Pop,
+    /// `keys(m)` returns the list of the keys of the map, ordered as described in [`Map::ordered_entries`].
+    Keys,
+    /// `has(m, k)` returns whether the map contains the key.
+    Has,
+    /// `delete(m, k)` removes the key from the map and returns its value, or nil if not found.
+    Delete,
+}
+
+impl Builtin {
+    pub(crate) const ALL: [Builtin; 7] = [
+        Builtin::CallCc,
+        Builtin::Len,
+        Builtin::Push,
+        Builtin::Pop,
+        Builtin::Keys,
+        Builtin::Has,
+        Builtin::Delete,
+    ];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Builtin::CallCc => "callcc",
+            Builtin::Len => "len",
+            Builtin::Push => "push",
+            Builtin::Pop => "pop",
+            Builtin::Keys => "keys",
+            Builtin::Has => "has",
+            Builtin::Delete => "delete",
+        }
+    }
+
+    /// The number of the arguments.
+    pub(crate) fn arity(&self) -> u8 {
+        match self {
+            Builtin::CallCc | Builtin::Len | Builtin::Pop | Builtin::Keys => 1,
+            Builtin::Push | Builtin::Has | Builtin::Delete => 2,
+        }
     }
 }
 
@@ -474,12 +1257,105 @@ pub(crate) enum Value {
     String(String),
     Function(Function),
     Closure(NonNull<Closure>),
+    Builtin(Builtin),
     Return(Continuation),
-    // Upvalue(NonNull<Upvalue>),
+    Continuation(NonNull<CapturedContinuation>),
+    Class(NonNull<Class>),
+    Instance(NonNull<Instance>),
+    BoundMethod(NonNull<BoundMethod>),
+    List(NonNull<List>),
+    Map(NonNull<Map>),
+    /// The stack slot whose variable is moved to the upvalue.
+    ///
+    /// This only appears in stack slots, and is never exposed to programs.
+    Upvalue(NonNull<Upvalue>),
 }
 
 impl Value {
+    /// Only nil and false are falsy, and the other values are truthy.
+    pub(crate) fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    /// The equality of `==`.
+    ///
+    /// Values of different types are never equal, and the closures and the continuations are compared by identity.
+    pub(crate) fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(lhs), Value::Boolean(rhs)) => lhs == rhs,
+            (Value::Number(lhs), Value::Number(rhs)) => lhs == rhs,
+            (Value::String(lhs), Value::String(rhs)) => lhs == rhs,
+            (Value::Function(lhs), Value::Function(rhs)) => Rc::ptr_eq(&lhs.chunk, &rhs.chunk),
+            (Value::Closure(lhs), Value::Closure(rhs)) => lhs == rhs,
+            (Value::Builtin(lhs), Value::Builtin(rhs)) => lhs == rhs,
+            (Value::Continuation(lhs), Value::Continuation(rhs)) => lhs == rhs,
+            (Value::Class(lhs), Value::Class(rhs)) => lhs == rhs,
+            (Value::Instance(lhs), Value::Instance(rhs)) => lhs == rhs,
+            (Value::BoundMethod(lhs), Value::BoundMethod(rhs)) => lhs == rhs,
+            (Value::List(lhs), Value::List(rhs)) => lhs == rhs,
+            (Value::Map(lhs), Value::Map(rhs)) => lhs == rhs,
+            _ => false,
+        }
+    }
+
+    /// The name of the type of this value used in error messages.
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::Closure(_) | Value::BoundMethod(_) => "function",
+            Value::Builtin(_) => "builtin",
+            Value::Return(_) => "return",
+            Value::Continuation(_) => "continuation",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Upvalue(_) => "upvalue",
+        }
+    }
+
+    /// The error message for calling this value with the wrong number of arguments.
+    pub(crate) fn arity_error(&self, arity: usize, arguments_len: usize) -> String {
+        format!(
+            "{} expects {} argument{} but got {}",
+            self.display(),
+            arity,
+            if arity == 1 { "" } else { "s" },
+            arguments_len
+        )
+    }
+
+    /// The text of this value when concatenated with a string.
+    ///
+    /// Only strings, numbers, booleans and nil are converted implicitly.
+    /// Unlike [`Value::display`] used by `print`, nil and booleans are converted
+    /// without the angle brackets, so that `"${nil}"` reads `nil` rather than `<nil>`.
+    pub(crate) fn to_text(&self) -> Option<String> {
+        match self {
+            Value::Nil => Some("nil".into()),
+            Value::Boolean(b) => Some(b.to_string()),
+            Value::Number(n) => Some(n.to_string()),
+            Value::String(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    /// The text of this value printed by `print` and shown in error messages.
+    ///
+    /// Nil and booleans are wrapped in angle brackets to tell them from strings,
+    /// which differs from [`Value::to_text`] used by concatenation and interpolation.
     pub(crate) fn display(&self) -> String {
+        self.display_nested(&mut vec![])
+    }
+
+    /// Display the value inside the lists and maps being displayed.
+    ///
+    /// A list or a map inside itself is displayed as `[...]` or `{...}`.
+    fn display_nested(&self, displaying: &mut Vec<NonNull<()>>) -> String {
         match self {
             Value::Nil => "<nil>".to_string(),
             Value::Boolean(b) => format!("<{}>", b),
@@ -490,18 +1366,70 @@ impl Value {
             Value::Closure(closure) => unsafe {
                 format!("<closure {}>", closure.as_ref().function.name)
             },
+            Value::Builtin(builtin) => format!("<builtin {}>", builtin.name()),
             Value::Return(continuation) => format!("<return {}>", continuation.display()),
-            // TODO: This is not safe...
-            // Value::Upvalue(upvalue) => unsafe {
-            //     format!(
-            //         "<upvalue {}>",
-            //         if upvalue.as_ref().is_closed() {
-            //             "closed"
-            //         } else {
-            //             "open"
-            //         }
-            //     )
-            // },
+            Value::Continuation(_) => "<continuation>".to_string(),
+            // SAFETY: the classes and the instances in the values are valid.
+            Value::Class(class) => unsafe { format!("<class {}>", class.as_ref().name) },
+            Value::Instance(instance) => unsafe {
+                format!("<{} instance>", instance.as_ref().class.as_ref().name)
+            },
+            // SAFETY: the bound methods in the values are valid.
+            Value::BoundMethod(bound) => unsafe { bound.as_ref().method.display() },
+            Value::List(list) if displaying.contains(&list.cast()) => "[...]".to_string(),
+            // SAFETY: the lists in the values are valid.
+            Value::List(list) => unsafe {
+                displaying.push(list.cast());
+                let elements = list
+                    .as_ref()
+                    .elements
+                    .iter()
+                    .map(|element| element.display_nested(displaying))
+                    .collect::<Vec<_>>();
+                displaying.pop();
+                format!("[{}]", elements.join(", "))
+            },
+            Value::Map(map) if displaying.contains(&map.cast()) => "{...}".to_string(),
+            // SAFETY: the maps in the values are valid.
+            Value::Map(map) => unsafe {
+                displaying.push(map.cast());
+                let entries = map
+                    .as_ref()
+                    .ordered_entries()
+                    .into_iter()
+                    .map(|(key, value)| {
+                        format!(
+                            "{}: {}",
+                            key.0.display_nested(displaying),
+                            value.display_nested(displaying)
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                displaying.pop();
+                format!("{{{}}}", entries.join(", "))
+            },
+            Value::Upvalue(_) => "<upvalue>".to_string(),
+        }
+    }
+
+    /// Mark the heap objects referred to by this value.
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Value::Nil
+            | Value::Boolean(_)
+            | Value::Number(_)
+            | Value::String(_)
+            | Value::Function(_)
+            | Value::Builtin(_) => {}
+            Value::Closure(closure) => tracer.mark(*closure),
+            Value::Return(continuation) => continuation.trace(tracer),
+            Value::Continuation(continuation) => tracer.mark(*continuation),
+            Value::Class(class) => tracer.mark(*class),
+            Value::Instance(instance) => tracer.mark(*instance),
+            Value::BoundMethod(bound) => tracer.mark(*bound),
+            Value::List(list) => tracer.mark(*list),
+            Value::Map(map) => tracer.mark(*map),
+            Value::Upvalue(upvalue) => tracer.mark(*upvalue),
         }
     }
 }
@@ -1,15 +1,52 @@
-use std::collections::HashMap;
+use std::{cmp::Ordering, collections::HashMap, fmt::Display, ptr::NonNull};
 
 use crate::{
-    allocator::LEAKING_ALLOCATOR,
+    allocator::{Heap, Trace},
     constant::{self, Constant},
     opcode::OpCode,
     side_effect::SideEffectHandler,
-    value::{self, Closure, Continuation, Value},
+    value::{
+        self, BoundMethod, Builtin, Class, Closure, Continuation, Instance, List, Map, MapKey,
+        Value,
+    },
 };
 
 use num_traits::FromPrimitive;
 
+/// A function being called when a runtime error is raised.
+#[derive(Debug)]
+pub(crate) struct StackFrame {
+    pub(crate) function: String,
+    /// The source line running in the function.
+    pub(crate) line: usize,
+}
+
+/// An error raised while running the program.
+#[derive(Debug)]
+pub(crate) struct RuntimeError {
+    pub(crate) message: String,
+    /// The offset of the failed instruction in the chunk.
+    pub(crate) offset: usize,
+    /// The source line of the failed instruction.
+    pub(crate) line: usize,
+    /// The functions being called, from the innermost one.
+    pub(crate) stack_trace: Vec<StackFrame>,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "runtime error at line {} (offset {}): {}",
+            self.line, self.offset, self.message
+        )?;
+        for frame in self.stack_trace.iter() {
+            write!(f, "\n    at {} (line {})", frame.function, frame.line)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 struct Global {
     definitions: HashMap<String, Value>,
@@ -19,6 +56,7 @@ pub(crate) struct Vm<'handler> {
     /// The current continuation to run the rest of the program.
     continuation: Continuation,
     global: Global,
+    heap: Heap,
     handler: &'handler mut (dyn SideEffectHandler + 'handler),
 }
 
@@ -29,12 +67,20 @@ impl<'stdout> Vm<'stdout> {
     ) -> Self {
         let function = value::Function::from(function);
         handler.call_function(&function).unwrap();
+        let mut heap = Heap::default();
+        let closure = heap.alloc(Closure::free(function));
         // SAFETY: We pass a valid closure object.
-        let continuation =
-            unsafe { Continuation::initial(LEAKING_ALLOCATOR.alloc(Closure::free(function))) };
+        let continuation = unsafe { Continuation::initial(&mut heap, closure) };
+        let mut global = Global::default();
+        for builtin in Builtin::ALL {
+            global
+                .definitions
+                .insert(builtin.name().into(), Value::Builtin(builtin));
+        }
         Vm {
             continuation,
-            global: Global::default(),
+            global,
+            heap,
             handler,
         }
     }
@@ -43,50 +89,324 @@ impl<'stdout> Vm<'stdout> {
         self.continuation.done()
     }
 
-    fn binop(&mut self, op: fn(f64, f64) -> f64) {
-        let rhs = self.continuation.stack_mut().pop().unwrap();
-        let lhs = self.continuation.stack_mut().pop().unwrap();
+    /// Run the program until it finishes or fails, reporting the runtime error to the handler.
+    pub(crate) fn run(&mut self) {
+        while !self.done() {
+            if let Err(error) = self.step() {
+                self.handler.runtime_error(&error).unwrap();
+                break;
+            }
+        }
+    }
+
+    fn binop(&mut self, name: &str, op: fn(f64, f64) -> f64) -> Result<(), String> {
+        let rhs = self.continuation.frame_mut().pop().unwrap();
+        let lhs = self.continuation.frame_mut().pop().unwrap();
 
         match (lhs, rhs) {
             (Value::Number(lhs), Value::Number(rhs)) => {
                 self.continuation
-                    .stack_mut()
+                    .frame_mut()
                     .push(Value::Number(op(lhs, rhs)));
                 self.continuation.advance(1);
+                Ok(())
+            }
+            (lhs, rhs) => Err(format!(
+                "unsupported operand types for {}: {} and {}",
+                name,
+                lhs.type_name(),
+                rhs.type_name()
+            )),
+        }
+    }
+
+    /// Add two numbers, or concatenate two values if either of them is a string.
+    fn add(&mut self) -> Result<(), String> {
+        let rhs = self.continuation.frame_mut().pop().unwrap();
+        let lhs = self.continuation.frame_mut().pop().unwrap();
+
+        let result = match (&lhs, &rhs) {
+            (Value::Number(lhs), Value::Number(rhs)) => Some(Value::Number(lhs + rhs)),
+            (Value::String(_), _) | (_, Value::String(_)) => lhs
+                .to_text()
+                .zip(rhs.to_text())
+                .map(|(lhs, rhs)| Value::String(lhs + &rhs)),
+            _ => None,
+        };
+        match result {
+            Some(result) => {
+                self.continuation.frame_mut().push(result);
+                self.continuation.advance(1);
+                Ok(())
+            }
+            None => Err(format!(
+                "unsupported operand types for +: {} and {}",
+                lhs.type_name(),
+                rhs.type_name()
+            )),
+        }
+    }
+
+    /// Compare two numbers or two strings.
+    ///
+    /// `op` receives `None` when the numbers are not ordered (i.e. NaN).
+    fn compare(&mut self, name: &str, op: fn(Option<Ordering>) -> bool) -> Result<(), String> {
+        let rhs = self.continuation.frame_mut().pop().unwrap();
+        let lhs = self.continuation.frame_mut().pop().unwrap();
+
+        let ordering = match (&lhs, &rhs) {
+            (Value::Number(lhs), Value::Number(rhs)) => lhs.partial_cmp(rhs),
+            (Value::String(lhs), Value::String(rhs)) => Some(lhs.cmp(rhs)),
+            _ => {
+                return Err(format!(
+                    "unsupported operand types for {}: {} and {}",
+                    name,
+                    lhs.type_name(),
+                    rhs.type_name()
+                ))
+            }
+        };
+        self.continuation
+            .frame_mut()
+            .push(Value::Boolean(op(ordering)));
+        self.continuation.advance(1);
+        Ok(())
+    }
+
+    fn equal(&mut self, negate: bool) {
+        let rhs = self.continuation.frame_mut().pop().unwrap();
+        let lhs = self.continuation.frame_mut().pop().unwrap();
+        self.continuation
+            .frame_mut()
+            .push(Value::Boolean(lhs.equals(&rhs) != negate));
+        self.continuation.advance(1);
+    }
+
+    fn call(&mut self, arguments_len: u8) -> Result<(), String> {
+        let callee = match self.continuation.callee(arguments_len) {
+            Value::Builtin(Builtin::CallCc) => self
+                .continuation
+                .call_with_current_continuation(&mut self.heap, arguments_len)?,
+            Value::Builtin(builtin) => return self.call_builtin(builtin, arguments_len),
+            Value::Continuation(captured) => {
+                return self
+                    .continuation
+                    .resume(&mut self.heap, captured, arguments_len);
+            }
+            Value::Class(class) => match self.instantiate(class, arguments_len)? {
+                Some(callee) => callee,
+                None => return Ok(()),
+            },
+            // SAFETY: the bound methods in the values are valid.
+            Value::BoundMethod(bound) => unsafe {
+                let bound = bound.as_ref();
+                self.continuation.call_method(
+                    &mut self.heap,
+                    bound.receiver(),
+                    bound.method(),
+                    arguments_len,
+                )?
+            },
+            _ => self.continuation.call(&mut self.heap, arguments_len)?,
+        };
+        self.enter(callee);
+        Ok(())
+    }
+
+    /// Run the builtin function other than `callcc`, replacing the callee and the arguments with the result.
+    fn call_builtin(&mut self, builtin: Builtin, arguments_len: u8) -> Result<(), String> {
+        if arguments_len != builtin.arity() {
+            return Err(Value::Builtin(builtin)
+                .arity_error(usize::from(builtin.arity()), usize::from(arguments_len)));
+        }
+        let frame = self.continuation.frame_mut();
+        let mut arguments = (0..arguments_len)
+            .map(|_| frame.pop().unwrap())
+            .collect::<Vec<_>>();
+        arguments.reverse();
+        frame.pop().unwrap();
+
+        // SAFETY: the lists in the values are valid.
+        let result = match (builtin, arguments.as_slice()) {
+            (Builtin::Len, [Value::List(list)]) => unsafe {
+                Value::Number(list.as_ref().len() as f64)
+            },
+            (Builtin::Len, [Value::String(s)]) => Value::Number(s.chars().count() as f64),
+            (Builtin::Len, [Value::Map(map)]) => unsafe {
+                Value::Number(map.as_ref().len() as f64)
+            },
+            (Builtin::Push, [Value::List(mut list), value]) => {
+                unsafe { list.as_mut().push(value.clone()) };
+                Value::Nil
+            }
+            (Builtin::Pop, [Value::List(mut list)]) => match unsafe { list.as_mut().pop() } {
+                Some(value) => value,
+                None => return Err("cannot pop from an empty list".into()),
+            },
+            (Builtin::Keys, [Value::Map(map)]) => {
+                let keys = unsafe { map.as_ref().keys() };
+                Value::List(self.heap.alloc(List::new(keys)))
+            }
+            (Builtin::Has, [Value::Map(map), key]) => {
+                let key = MapKey::new(key.clone())?;
+                Value::Boolean(unsafe { map.as_ref().contains(&key) })
+            }
+            (Builtin::Delete, [Value::Map(mut map), key]) => {
+                let key = MapKey::new(key.clone())?;
+                unsafe { map.as_mut().delete(&key) }.unwrap_or(Value::Nil)
+            }
+            (_, [argument, ..]) => {
+                return Err(format!(
+                    "{} cannot take {}",
+                    Value::Builtin(builtin).display(),
+                    argument.type_name()
+                ))
+            }
+            (_, []) => unreachable!("builtins take at least 1 argument"),
+        };
+        self.continuation.frame_mut().push(result);
+        Ok(())
+    }
+
+    /// Create an instance of the class called with the given number of arguments.
+    ///
+    /// If the class has `init`, it's called with the instance and returns the closure to run.
+    fn instantiate(
+        &mut self,
+        class: NonNull<Class>,
+        arguments_len: u8,
+    ) -> Result<Option<NonNull<Closure>>, String> {
+        let instance = Value::Instance(self.heap.alloc(Instance::new(class)));
+        // SAFETY: the classes in the values are valid.
+        match unsafe { class.as_ref().method("init") } {
+            Some(init) => self
+                .continuation
+                .call_method(&mut self.heap, instance, init, arguments_len)
+                .map(Some),
+            None if arguments_len != 0 => {
+                Err(Value::Class(class).arity_error(0, usize::from(arguments_len)))
+            }
+            None => {
+                let frame = self.continuation.frame_mut();
+                frame.pop().unwrap();
+                frame.push(instance);
+                Ok(None)
             }
-            _ => panic!("bad type"),
         }
     }
 
-    fn call(&mut self, arguments_len: u8) {
-        let callee = self.continuation.call(arguments_len);
+    /// Look up the method of the class, and bind it to the receiver.
+    fn bind_method(
+        &mut self,
+        class: NonNull<Class>,
+        receiver: Value,
+        name: &str,
+    ) -> Result<Value, String> {
+        // SAFETY: the classes in the values are valid.
+        match unsafe { class.as_ref().method(name) } {
+            Some(method) => Ok(Value::BoundMethod(
+                self.heap.alloc(BoundMethod::new(receiver, method)),
+            )),
+            None => Err(format!("undefined property {}", name)),
+        }
+    }
+
+    /// Call the method of the class with the receiver and the arguments on the top of the stack.
+    fn invoke_from_class(
+        &mut self,
+        class: NonNull<Class>,
+        receiver: Value,
+        name: &str,
+        arguments_len: u8,
+    ) -> Result<(), String> {
+        // SAFETY: the classes in the values are valid.
+        let method = match unsafe { class.as_ref().method(name) } {
+            Some(method) => method,
+            None => return Err(format!("undefined property {}", name)),
+        };
+        let callee =
+            self.continuation
+                .call_method(&mut self.heap, receiver, method, arguments_len)?;
+        self.enter(callee);
+        Ok(())
+    }
+
+    /// Pop the superclass pushed for `super`.
+    fn pop_superclass(&mut self) -> NonNull<Class> {
+        match self.continuation.frame_mut().pop().unwrap() {
+            Value::Class(class) => class,
+            _ => unreachable!("compile error: super must be a class"),
+        }
+    }
+
+    /// The name given by the string constant operand of the current instruction.
+    fn name_operand(&self) -> String {
+        match self.continuation.constant(self.continuation.code(1)) {
+            Constant::String(name) => name.clone(),
+            _ => unreachable!("compile error: the operand must be a string constant"),
+        }
+    }
+
+    fn enter(&mut self, callee: NonNull<Closure>) {
         // TODO: the safety of this block relies on the validity of the callee in the stack.
         let function = unsafe { callee.as_ref().function() };
         self.handler.call_function(function).unwrap();
     }
 
-    pub(crate) fn step(&mut self) {
+    /// Reclaim the heap objects unreachable from the running continuation and the globals.
+    fn collect_garbage(&mut self) {
+        let continuation = &self.continuation;
+        let global = &self.global;
+        // SAFETY: the values referred to by the VM are reachable from the continuation and the globals.
+        unsafe {
+            self.heap.collect(|tracer| {
+                continuation.trace(tracer);
+                for value in global.definitions.values() {
+                    value.trace(tracer);
+                }
+            })
+        };
+    }
+
+    /// Run the instruction at the current continuation.
+    pub(crate) fn step(&mut self) -> Result<(), RuntimeError> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        let offset = self.continuation.ip();
+        self.execute().map_err(|message| RuntimeError {
+            message,
+            offset,
+            // Errors are raised before switching to another function,
+            // so the offset points to the instruction of the current chunk.
+            line: self.continuation.chunk().line(offset),
+            stack_trace: self.continuation.stack_trace(offset),
+        })
+    }
+
+    fn execute(&mut self) -> Result<(), String> {
         let opcode = OpCode::from_u8(self.continuation.current_code());
         match opcode {
             None => panic!("unknown opcode"),
             Some(OpCode::Nil) => {
-                self.continuation.stack_mut().push(Value::Nil);
+                self.continuation.frame_mut().push(Value::Nil);
                 self.continuation.advance(1);
             }
             Some(OpCode::True) => {
-                self.continuation.stack_mut().push(Value::Boolean(true));
+                self.continuation.frame_mut().push(Value::Boolean(true));
                 self.continuation.advance(1);
             }
             Some(OpCode::False) => {
-                self.continuation.stack_mut().push(Value::Boolean(false));
+                self.continuation.frame_mut().push(Value::Boolean(false));
                 self.continuation.advance(1);
             }
             Some(OpCode::Pop) => {
-                self.continuation.stack_mut().pop().unwrap();
+                self.continuation.frame_mut().pop().unwrap();
                 self.continuation.advance(1);
             }
             Some(OpCode::Print) => {
-                let value = self.continuation.stack_mut().pop().unwrap();
+                let value = self.continuation.frame_mut().pop().unwrap();
                 self.handler.print(&value.display()).unwrap();
                 self.continuation.advance(1);
             }
@@ -95,28 +415,164 @@ impl<'stdout> Vm<'stdout> {
                 // Return to the next opcode of OP_CALL.
                 self.continuation.advance(2);
 
-                self.call(arguments_len);
+                self.call(arguments_len)?;
             }
             Some(OpCode::Return) => {
-                self.continuation.perform_return();
+                self.continuation.perform_return(&mut self.heap)?;
+            }
+            Some(OpCode::Reset) => {
+                // Return to the next opcode of OP_RESET.
+                self.continuation.advance(1);
+
+                let callee = self.continuation.reset(&mut self.heap)?;
+                self.enter(callee);
+            }
+            Some(OpCode::Shift) => {
+                // Resume from the next opcode of OP_SHIFT.
+                self.continuation.advance(1);
+
+                let callee = self.continuation.shift(&mut self.heap)?;
+                self.enter(callee);
+            }
+            Some(OpCode::Jump) => {
+                let jump = self.continuation.jump_offset();
+                self.continuation.advance(3 + jump);
+            }
+            Some(OpCode::JumpIfFalse) => {
+                // The condition is left on the stack, and the compiler emits OP_POP for both branches.
+                let jump = if self.continuation.frame_mut().peek(0).is_truthy() {
+                    0
+                } else {
+                    self.continuation.jump_offset()
+                };
+                self.continuation.advance(3 + jump);
+            }
+            Some(OpCode::Loop) => {
+                let jump = self.continuation.jump_offset();
+                self.continuation.advance(3);
+                self.continuation.retreat(jump);
             }
             Some(OpCode::Constant) => {
                 let index = self.continuation.code(1);
                 let constant = self.continuation.constant(index).clone();
-                self.continuation.stack_mut().push(constant.into());
+                self.continuation.frame_mut().push(constant.into());
+                self.continuation.advance(2);
+            }
+            Some(OpCode::Not) => {
+                let value = self.continuation.frame_mut().pop().unwrap();
+                self.continuation
+                    .frame_mut()
+                    .push(Value::Boolean(!value.is_truthy()));
+                self.continuation.advance(1);
+            }
+            Some(OpCode::Negate) => match self.continuation.frame_mut().pop().unwrap() {
+                Value::Number(n) => {
+                    self.continuation.frame_mut().push(Value::Number(-n));
+                    self.continuation.advance(1);
+                }
+                value => {
+                    return Err(format!(
+                        "unsupported operand type for -: {}",
+                        value.type_name()
+                    ))
+                }
+            },
+            Some(OpCode::Add) => self.add()?,
+            Some(OpCode::Sub) => self.binop("-", |lhs, rhs| lhs - rhs)?,
+            Some(OpCode::Mul) => self.binop("*", |lhs, rhs| lhs * rhs)?,
+            Some(OpCode::Div) => self.binop("/", |lhs, rhs| lhs / rhs)?,
+            Some(OpCode::Concat) => {
+                let parts_len = usize::from(self.continuation.code(1));
+                let frame = self.continuation.frame_mut();
+                let mut parts = (0..parts_len)
+                    .map(|_| frame.pop().unwrap())
+                    .collect::<Vec<_>>();
+                parts.reverse();
+                let mut text = String::new();
+                for part in parts.iter() {
+                    match part.to_text() {
+                        Some(part) => text.push_str(&part),
+                        None => return Err(format!("cannot interpolate {}", part.type_name())),
+                    }
+                }
+                self.continuation.frame_mut().push(Value::String(text));
+                self.continuation.advance(2);
+            }
+            Some(OpCode::List) => {
+                let elements_len = usize::from(self.continuation.code(1));
+                let frame = self.continuation.frame_mut();
+                let mut elements = (0..elements_len)
+                    .map(|_| frame.pop().unwrap())
+                    .collect::<Vec<_>>();
+                elements.reverse();
+                let list = self.heap.alloc(List::new(elements));
+                self.continuation.frame_mut().push(Value::List(list));
                 self.continuation.advance(2);
             }
-            Some(OpCode::Add) => self.binop(|lhs, rhs| lhs + rhs),
-            Some(OpCode::Sub) => self.binop(|lhs, rhs| lhs - rhs),
-            Some(OpCode::Mul) => self.binop(|lhs, rhs| lhs * rhs),
-            Some(OpCode::Div) => self.binop(|lhs, rhs| lhs / rhs),
+            Some(OpCode::Map) => {
+                let entries_len = usize::from(self.continuation.code(1));
+                let frame = self.continuation.frame_mut();
+                let mut entries = (0..entries_len)
+                    .map(|_| {
+                        let value = frame.pop().unwrap();
+                        let key = frame.pop().unwrap();
+                        (key, value)
+                    })
+                    .collect::<Vec<_>>();
+                // Insert in the source order, so that the later entries win.
+                entries.reverse();
+                let entries = entries
+                    .into_iter()
+                    .map(|(key, value)| Ok((MapKey::new(key)?, value)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                let map = self.heap.alloc(Map::new(entries));
+                self.continuation.frame_mut().push(Value::Map(map));
+                self.continuation.advance(2);
+            }
+            Some(OpCode::GetIndex) => {
+                let index = self.continuation.frame_mut().pop().unwrap();
+                let value = match self.continuation.frame_mut().pop().unwrap() {
+                    // SAFETY: the lists in the values are valid.
+                    Value::List(list) => unsafe { list.as_ref().get(&index)? },
+                    // SAFETY: the maps in the values are valid.
+                    Value::Map(map) => unsafe { map.as_ref().get(&MapKey::new(index)?)? },
+                    object => return Err(format!("cannot index {}", object.type_name())),
+                };
+                self.continuation.frame_mut().push(value);
+                self.continuation.advance(1);
+            }
+            Some(OpCode::SetIndex) => {
+                let value = self.continuation.frame_mut().pop().unwrap();
+                let index = self.continuation.frame_mut().pop().unwrap();
+                match self.continuation.frame_mut().pop().unwrap() {
+                    // SAFETY: the lists in the values are valid.
+                    Value::List(mut list) => unsafe { list.as_mut().set(&index, value)? },
+                    // SAFETY: the maps in the values are valid.
+                    Value::Map(mut map) => unsafe { map.as_mut().set(MapKey::new(index)?, value) },
+                    object => return Err(format!("cannot index {}", object.type_name())),
+                }
+                self.continuation.advance(1);
+            }
+            Some(OpCode::Equal) => self.equal(false),
+            Some(OpCode::NotEqual) => self.equal(true),
+            Some(OpCode::Less) => self.compare("<", |o| o == Some(Ordering::Less))?,
+            Some(OpCode::LessEqual) => self.compare("<=", |o| {
+                matches!(o, Some(Ordering::Less | Ordering::Equal))
+            })?,
+            Some(OpCode::Greater) => self.compare(">", |o| o == Some(Ordering::Greater))?,
+            Some(OpCode::GreaterEqual) => self.compare(">=", |o| {
+                matches!(o, Some(Ordering::Greater | Ordering::Equal))
+            })?,
             Some(OpCode::GetGlobal) => {
                 let index = self.continuation.code(1);
                 let constant = self.continuation.constant(index);
                 match constant {
                     Constant::String(name) => {
-                        let value = self.global.definitions[name].clone();
-                        self.continuation.stack_mut().push(value);
+                        let value = match self.global.definitions.get(name) {
+                            Some(value) => value.clone(),
+                            None => return Err(format!("undefined variable {}", name)),
+                        };
+                        self.continuation.frame_mut().push(value);
                         self.continuation.advance(2);
                     }
                     _ => unreachable!("compile error: OP_GET_GLOBAL takes a string constant"),
@@ -127,7 +583,7 @@ impl<'stdout> Vm<'stdout> {
                 let constant = self.continuation.constant(index).clone();
                 match constant {
                     Constant::String(name) => {
-                        let value = self.continuation.stack_mut().pop().unwrap();
+                        let value = self.continuation.frame_mut().pop().unwrap();
                         self.global.definitions.insert(name, value);
                         self.continuation.advance(2);
                     }
@@ -136,37 +592,166 @@ impl<'stdout> Vm<'stdout> {
             }
             Some(OpCode::GetLocal) => {
                 let offset = self.continuation.code(1);
-                let value = self.continuation.stack_mut().get_local(offset);
-                self.continuation.stack_mut().push(value);
+                let value = self.continuation.frame_mut().get_local(offset);
+                self.continuation.frame_mut().push(value);
                 self.continuation.advance(2);
             }
             Some(OpCode::SetLocal) => {
                 let offset = self.continuation.code(1);
-                let value = self.continuation.stack_mut().pop().unwrap();
-                self.continuation.stack_mut().set_local(offset, value);
+                let value = self.continuation.frame_mut().pop().unwrap();
+                self.continuation.frame_mut().set_local(offset, value);
                 self.continuation.advance(2);
             }
             Some(OpCode::Closure) => {
-                self.continuation.perform_closure();
+                self.continuation.perform_closure(&mut self.heap);
+            }
+            Some(OpCode::Class) => {
+                let class = self.heap.alloc(Class::new(self.name_operand()));
+                self.continuation.frame_mut().push(Value::Class(class));
+                self.continuation.advance(2);
+            }
+            Some(OpCode::GetProperty) => {
+                let name = self.name_operand();
+                let value = match self.continuation.frame_mut().pop().unwrap() {
+                    // The fields shadow the methods.
+                    // SAFETY: the instances in the values are valid.
+                    Value::Instance(instance) => match unsafe { instance.as_ref().get(&name) } {
+                        Some(value) => value,
+                        None => {
+                            let class = unsafe { instance.as_ref().class() };
+                            self.bind_method(class, Value::Instance(instance), &name)?
+                        }
+                    },
+                    object => {
+                        return Err(format!(
+                            "cannot get property {} of {}",
+                            name,
+                            object.type_name()
+                        ))
+                    }
+                };
+                self.continuation.frame_mut().push(value);
+                self.continuation.advance(2);
+            }
+            Some(OpCode::SetProperty) => {
+                let name = self.name_operand();
+                let value = self.continuation.frame_mut().pop().unwrap();
+                match self.continuation.frame_mut().pop().unwrap() {
+                    // SAFETY: the instances in the values are valid.
+                    Value::Instance(mut instance) => unsafe { instance.as_mut().set(name, value) },
+                    object => {
+                        return Err(format!(
+                            "cannot set property {} of {}",
+                            name,
+                            object.type_name()
+                        ))
+                    }
+                }
+                self.continuation.advance(2);
+            }
+            Some(OpCode::Method) => {
+                let name = self.name_operand();
+                let method = self.continuation.frame_mut().pop().unwrap();
+                match self.continuation.frame_mut().peek(0) {
+                    // SAFETY: the classes in the values are valid.
+                    Value::Class(mut class) => unsafe { class.as_mut().add_method(name, method) },
+                    _ => unreachable!("compile error: OP_METHOD must follow a class"),
+                }
+                self.continuation.advance(2);
+            }
+            Some(OpCode::Inherit) => {
+                let mut subclass = match self.continuation.frame_mut().pop().unwrap() {
+                    Value::Class(class) => class,
+                    _ => unreachable!("compile error: OP_INHERIT must follow a class"),
+                };
+                match self.continuation.frame_mut().peek(0) {
+                    // SAFETY: the classes in the values are valid, and a class never inherits from itself.
+                    Value::Class(superclass) => unsafe {
+                        subclass.as_mut().inherit(superclass.as_ref())
+                    },
+                    superclass => {
+                        return Err(format!(
+                            "superclass must be a class, not {}",
+                            superclass.type_name()
+                        ))
+                    }
+                }
+                self.continuation.advance(1);
+            }
+            Some(OpCode::Invoke) => {
+                let name = self.name_operand();
+                let arguments_len = self.continuation.code(2);
+                // Return to the next opcode of OP_INVOKE.
+                self.continuation.advance(3);
+
+                let receiver = self
+                    .continuation
+                    .frame_mut()
+                    .peek(usize::from(arguments_len));
+                match receiver {
+                    // SAFETY: the instances in the values are valid.
+                    Value::Instance(instance) => match unsafe { instance.as_ref().get(&name) } {
+                        // Call the function stored in the field as a usual call.
+                        Some(field) => {
+                            self.continuation
+                                .frame_mut()
+                                .replace(usize::from(arguments_len), field);
+                            self.call(arguments_len)?;
+                        }
+                        None => {
+                            let class = unsafe { instance.as_ref().class() };
+                            self.invoke_from_class(class, receiver, &name, arguments_len)?;
+                        }
+                    },
+                    receiver => {
+                        return Err(format!(
+                            "cannot get property {} of {}",
+                            name,
+                            receiver.type_name()
+                        ))
+                    }
+                }
+            }
+            Some(OpCode::GetSuper) => {
+                let name = self.name_operand();
+                let superclass = self.pop_superclass();
+                let receiver = self.continuation.frame_mut().pop().unwrap();
+                let method = self.bind_method(superclass, receiver, &name)?;
+                self.continuation.frame_mut().push(method);
+                self.continuation.advance(2);
+            }
+            Some(OpCode::SuperInvoke) => {
+                let name = self.name_operand();
+                let arguments_len = self.continuation.code(2);
+                // Return to the next opcode of OP_SUPER_INVOKE.
+                self.continuation.advance(3);
+
+                let superclass = self.pop_superclass();
+                let receiver = self
+                    .continuation
+                    .frame_mut()
+                    .peek(usize::from(arguments_len));
+                self.invoke_from_class(superclass, receiver, &name, arguments_len)?;
             }
             Some(OpCode::CloseUpvalue) => {
                 // close the upvalue pointing to the top of the stack.
-                let new_sp = self.continuation.stack_mut().sp() - 1;
+                let new_sp = self.continuation.frame_mut().sp() - 1;
                 self.continuation.close_upvalue(new_sp);
                 self.continuation.advance(1);
             }
             Some(OpCode::GetUpvalue) => {
                 let offset = self.continuation.code(1);
                 let value = self.continuation.get_upvalue(offset);
-                self.continuation.stack_mut().push(value);
+                self.continuation.frame_mut().push(value);
                 self.continuation.advance(2);
             }
             Some(OpCode::SetUpvalue) => {
                 let offset = self.continuation.code(1);
-                let value = self.continuation.stack_mut().pop().unwrap();
+                let value = self.continuation.frame_mut().pop().unwrap();
                 self.continuation.set_upvalue(offset, value);
                 self.continuation.advance(2);
             }
         }
+        Ok(())
     }
 }